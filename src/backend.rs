@@ -0,0 +1,68 @@
+//! The car being driven, chosen with the `BACKEND` environment variable: `simulator` (default)
+//! connects to the simulator at `SIMULATOR`, `raspi` drives the physical car through the camera
+//! and motor sockets in `Config::raspi`.
+
+use std::time::Duration;
+
+use anyhow::bail;
+
+use crate::{
+    config::Config,
+    connection::{Command, Connection, LoginMessage},
+    error::Result,
+    frame::ReceivedFrame,
+    raspi::RaspiConnection,
+};
+
+pub enum Backend {
+    Simulator(Connection),
+    Raspi(RaspiConnection),
+}
+
+impl Backend {
+    pub fn from_env(config: &Config, login_message: &LoginMessage) -> anyhow::Result<Backend> {
+        match std::env::var("BACKEND").as_deref() {
+            Ok("simulator") | Err(_) => {
+                let address = std::env::var("SIMULATOR").unwrap_or(String::from("127.0.0.1:11000"));
+                let connection = Connection::connect(&address, login_message, &config.connection)?;
+                Ok(Backend::Simulator(connection))
+            }
+            Ok("raspi") => Ok(Backend::Raspi(RaspiConnection::connect(
+                &config.raspi,
+                &config.frames,
+            )?)),
+            Ok(backend) => bail!("Unknown backend {}", backend),
+        }
+    }
+
+    pub fn send(&mut self, command: &Command) -> Result<()> {
+        match self {
+            Backend::Simulator(connection) => connection.send(command),
+            Backend::Raspi(connection) => connection.send(command),
+        }
+    }
+
+    /// Waits until the next image starts arriving. Returns false if it didn't within `timeout`.
+    pub fn wait_for_image(&mut self, timeout: Duration) -> Result<bool> {
+        match self {
+            Backend::Simulator(connection) => connection.wait_for_image(timeout),
+            Backend::Raspi(connection) => connection.wait_for_image(timeout),
+        }
+    }
+
+    pub fn read_next_image(&mut self) -> Result<ReceivedFrame> {
+        match self {
+            Backend::Simulator(connection) => {
+                connection.read_next_image().map(ReceivedFrame::Bytes)
+            }
+            Backend::Raspi(connection) => connection.read_next_image(),
+        }
+    }
+
+    pub fn close(&mut self) {
+        match self {
+            Backend::Simulator(connection) => connection.close(),
+            Backend::Raspi(connection) => connection.close(),
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! Camera calibration: focal length, lens distortion and how the camera is mounted.
+//!
+//! `cargo run -- calibrate <image dir> [--board 9x6] [--square 0.025] [--ground image]
+//! [--height M] [--pitch DEG] [--save]`
+//!
+//! The images are photos of a checkerboard with the given number of inner corners and square
+//! size in meters, taken with `RECORD=1` so they match the preprocessed frames the pipeline
+//! sees. The intrinsics are estimated from all of them. The mounting height and pitch come from
+//! `--ground`, an image of the checkerboard lying flat on the track, or are given directly with
+//! `--height` and `--pitch`. The result is printed as the `calibration` config section, `--save`
+//! writes it to the config file.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail};
+use opencv::{
+    calib3d::{
+        calibrate_camera, find_chessboard_corners, init_undistort_rectify_map, rodrigues,
+        solve_pnp, undistort_points, CALIB_CB_ADAPTIVE_THRESH, CALIB_CB_NORMALIZE_IMAGE,
+        SOLVEPNP_ITERATIVE,
+    },
+    core::{
+        no_array, Point2f, Point3f, Scalar_, Size, TermCriteria, TermCriteria_Type,
+        BORDER_CONSTANT, CV_32FC1,
+    },
+    imgcodecs,
+    imgproc::{corner_sub_pix, cvt_color, remap, COLOR_BGR2GRAY, INTER_LINEAR},
+    prelude::*,
+    types::{
+        VectorOfMat, VectorOfPoint2f, VectorOfPoint3f, VectorOfVectorOfPoint2f,
+        VectorOfVectorOfPoint3f,
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{config, error::Result, recording::image_files};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraCalibration {
+    /// Size of the frames the calibration was made with.
+    pub width: i32,
+    pub height: i32,
+    /// Focal lengths and principal point, in pixels.
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+    /// k1, k2, p1, p2 and k3 of OpenCV's distortion model.
+    pub distortion: [f64; 5],
+    /// Height of the lens above the track, in meters.
+    pub camera_height: f64,
+    /// Downward tilt of the camera, in degrees.
+    pub pitch_deg: f64,
+    /// Undistort every frame before `process_frame`.
+    #[serde(default)]
+    pub undistort: bool,
+}
+
+impl CameraCalibration {
+    fn camera_matrix(&self) -> Result<Mat> {
+        Ok(Mat::from_slice_2d(&[
+            [self.fx, 0.0, self.cx],
+            [0.0, self.fy, self.cy],
+            [0.0, 0.0, 1.0],
+        ])?)
+    }
+
+    fn distortion_coefficients(&self) -> Result<Mat> {
+        Ok(Mat::from_slice(&self.distortion)?)
+    }
+}
+
+/// A calibrated camera, ready to undistort frames and map pixels to the track.
+pub struct Camera {
+    calibration: CameraCalibration,
+    camera_matrix: Mat,
+    distortion: Mat,
+    /// Lookup tables for `remap`, only computed when frames are undistorted.
+    maps: Option<(Mat, Mat)>,
+}
+
+impl Camera {
+    pub fn new(calibration: CameraCalibration) -> Result<Camera> {
+        let camera_matrix = calibration.camera_matrix()?;
+        let distortion = calibration.distortion_coefficients()?;
+
+        let maps = if calibration.undistort {
+            let mut map_x = Mat::default()?;
+            let mut map_y = Mat::default()?;
+            init_undistort_rectify_map(
+                &camera_matrix,
+                &distortion,
+                &no_array()?,
+                &camera_matrix,
+                Size {
+                    width: calibration.width,
+                    height: calibration.height,
+                },
+                CV_32FC1,
+                &mut map_x,
+                &mut map_y,
+            )?;
+            Some((map_x, map_y))
+        } else {
+            None
+        };
+
+        Ok(Camera {
+            calibration,
+            camera_matrix,
+            distortion,
+            maps,
+        })
+    }
+
+    /// Removes the lens distortion from a frame if `CameraCalibration::undistort` is set.
+    pub fn undistort(&self, frame: Mat) -> Result<Mat> {
+        let (map_x, map_y) = match &self.maps {
+            Some(maps) => maps,
+            None => return Ok(frame),
+        };
+
+        let mut undistorted = Mat::default()?;
+        remap(
+            &frame,
+            &mut undistorted,
+            map_x,
+            map_y,
+            INTER_LINEAR,
+            BORDER_CONSTANT,
+            Scalar_([0.0, 0.0, 0.0, 0.0]),
+        )?;
+        Ok(undistorted)
+    }
+
+    /// Position on the track of a pixel of a frame that went through `undistort`, in meters to
+    /// the right of and ahead of the camera. None for pixels at or above the horizon.
+    pub fn pixel_to_ground(&self, x: f32, y: f32) -> Result<Option<(f32, f32)>> {
+        let (ray_x, ray_y) = if self.maps.is_some() {
+            let c = &self.calibration;
+            ((x as f64 - c.cx) / c.fx, (y as f64 - c.cy) / c.fy)
+        } else {
+            let mut points = VectorOfPoint2f::new();
+            points.push(Point2f { x, y });
+            let mut normalized = VectorOfPoint2f::new();
+            undistort_points(
+                &points,
+                &mut normalized,
+                &self.camera_matrix,
+                &self.distortion,
+                &no_array()?,
+                &no_array()?,
+            )?;
+            let point = normalized.get(0)?;
+            (point.x as f64, point.y as f64)
+        };
+
+        // Camera coordinates have y pointing down and z forward, tilt them by the pitch to get
+        // the ray's forward and downward components relative to the track.
+        let pitch = self.calibration.pitch_deg.to_radians();
+        let forward = pitch.cos() - ray_y * pitch.sin();
+        let down = ray_y * pitch.cos() + pitch.sin();
+        if down <= 1e-6 {
+            return Ok(None);
+        }
+
+        let distance = self.calibration.camera_height / down;
+        Ok(Some((
+            (distance * ray_x) as f32,
+            (distance * forward) as f32,
+        )))
+    }
+}
+
+struct Board {
+    columns: i32,
+    rows: i32,
+    square: f32,
+}
+
+impl Board {
+    fn size(&self) -> Size {
+        Size {
+            width: self.columns,
+            height: self.rows,
+        }
+    }
+
+    fn object_points(&self) -> VectorOfPoint3f {
+        let mut points = VectorOfPoint3f::new();
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                points.push(Point3f {
+                    x: column as f32 * self.square,
+                    y: row as f32 * self.square,
+                    z: 0.0,
+                });
+            }
+        }
+        points
+    }
+
+    fn find_corners(&self, path: &Path) -> anyhow::Result<Option<(Size, VectorOfPoint2f)>> {
+        let image = imgcodecs::imread(&path.to_string_lossy(), imgcodecs::IMREAD_COLOR)?;
+        if image.empty()? {
+            bail!("Could not read {}", path.display());
+        }
+
+        let mut gray = Mat::default()?;
+        cvt_color(&image, &mut gray, COLOR_BGR2GRAY, 0)?;
+
+        let mut corners = VectorOfPoint2f::new();
+        let found = find_chessboard_corners(
+            &gray,
+            self.size(),
+            &mut corners,
+            CALIB_CB_ADAPTIVE_THRESH | CALIB_CB_NORMALIZE_IMAGE,
+        )?;
+        if !found {
+            return Ok(None);
+        }
+
+        corner_sub_pix(
+            &gray,
+            &mut corners,
+            Size {
+                width: 3,
+                height: 3,
+            },
+            Size {
+                width: -1,
+                height: -1,
+            },
+            TermCriteria::new(
+                TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32,
+                30,
+                0.01,
+            )?,
+        )?;
+
+        let size = Size {
+            width: image.cols(),
+            height: image.rows(),
+        };
+        Ok(Some((size, corners)))
+    }
+}
+
+fn estimate_intrinsics(board: &Board, dir: &Path) -> anyhow::Result<CameraCalibration> {
+    let mut object_points = VectorOfVectorOfPoint3f::new();
+    let mut image_points = VectorOfVectorOfPoint2f::new();
+    let mut image_size = None;
+
+    for path in image_files(dir)? {
+        match board.find_corners(&path)? {
+            Some((size, corners)) => {
+                if image_size.is_some_and(|image_size| image_size != size) {
+                    bail!(
+                        "{} has a different size than the other images",
+                        path.display()
+                    );
+                }
+                image_size = Some(size);
+                object_points.push(board.object_points());
+                image_points.push(corners);
+                println!("found board in {}", path.display());
+            }
+            None => println!("no board in    {}", path.display()),
+        }
+    }
+
+    let image_size = match image_size {
+        Some(size) if image_points.len() >= 3 => size,
+        _ => bail!("Need at least 3 images with the whole board visible"),
+    };
+
+    let mut camera_matrix = Mat::default()?;
+    let mut distortion = Mat::default()?;
+    let mut rotations = VectorOfMat::new();
+    let mut translations = VectorOfMat::new();
+    let error = calibrate_camera(
+        &object_points,
+        &image_points,
+        image_size,
+        &mut camera_matrix,
+        &mut distortion,
+        &mut rotations,
+        &mut translations,
+        0,
+        TermCriteria::new(
+            TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32,
+            30,
+            f64::EPSILON,
+        )?,
+    )?;
+    println!("Reprojection error {:.3} px", error);
+
+    let mut coefficients = [0.0; 5];
+    for (i, coefficient) in coefficients.iter_mut().enumerate() {
+        *coefficient = *distortion.at::<f64>(i as i32)?;
+    }
+
+    Ok(CameraCalibration {
+        width: image_size.width,
+        height: image_size.height,
+        fx: *camera_matrix.at_2d::<f64>(0, 0)?,
+        fy: *camera_matrix.at_2d::<f64>(1, 1)?,
+        cx: *camera_matrix.at_2d::<f64>(0, 2)?,
+        cy: *camera_matrix.at_2d::<f64>(1, 2)?,
+        distortion: coefficients,
+        camera_height: 0.0,
+        pitch_deg: 0.0,
+        undistort: false,
+    })
+}
+
+/// Finds the camera's height and pitch from an image of the board lying on the track.
+fn estimate_mounting(
+    board: &Board,
+    calibration: &mut CameraCalibration,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let corners = match board.find_corners(path)? {
+        Some((_, corners)) => corners,
+        None => bail!("No board in {}", path.display()),
+    };
+
+    let mut rotation = Mat::default()?;
+    let mut translation = Mat::default()?;
+    solve_pnp(
+        &board.object_points(),
+        &corners,
+        &calibration.camera_matrix()?,
+        &calibration.distortion_coefficients()?,
+        &mut rotation,
+        &mut translation,
+        false,
+        SOLVEPNP_ITERATIVE,
+    )?;
+
+    let mut rotation_matrix = Mat::default()?;
+    rodrigues(&rotation, &mut rotation_matrix, &mut no_array()?)?;
+
+    // The board's z axis is the track's normal, expressed in camera coordinates by the third
+    // column of the rotation. Flip it to point from the track towards the camera.
+    let mut distance = 0.0;
+    let mut normal = [0.0; 3];
+    for (i, n) in normal.iter_mut().enumerate() {
+        *n = *rotation_matrix.at_2d::<f64>(i as i32, 2)?;
+        distance += *n * *translation.at::<f64>(i as i32)?;
+    }
+
+    let up = if distance < 0.0 { 1.0 } else { -1.0 };
+
+    calibration.camera_height = distance.abs();
+    calibration.pitch_deg = (-up * normal[2]).asin().to_degrees();
+    Ok(())
+}
+
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let mut positional = Vec::new();
+    let mut board = Board {
+        columns: 9,
+        rows: 6,
+        square: 0.025,
+    };
+    let mut ground = None;
+    let mut camera_height = None;
+    let mut pitch_deg = None;
+    let mut save = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+
+        match arg.as_str() {
+            "--board" => {
+                let value = value()?;
+                let (columns, rows) = value
+                    .split_once('x')
+                    .ok_or_else(|| anyhow!("--board is COLUMNSxROWS, got {}", value))?;
+                board.columns = columns.parse()?;
+                board.rows = rows.parse()?;
+            }
+            "--square" => board.square = value()?.parse()?,
+            "--ground" => ground = Some(PathBuf::from(value()?)),
+            "--height" => camera_height = Some(value()?.parse()?),
+            "--pitch" => pitch_deg = Some(value()?.parse()?),
+            "--save" => save = true,
+            _ => positional.push(arg),
+        }
+    }
+
+    let dir = match positional.first() {
+        Some(dir) => Path::new(dir.as_str()),
+        None => bail!(
+            "Usage: calibrate <image dir> [--board 9x6] [--square 0.025] [--ground image] [--height M] [--pitch DEG] [--save]"
+        ),
+    };
+
+    let mut calibration = estimate_intrinsics(&board, dir)?;
+    if let Some(ground) = ground {
+        estimate_mounting(&board, &mut calibration, &ground)?;
+    }
+    if let Some(camera_height) = camera_height {
+        calibration.camera_height = camera_height;
+    }
+    if let Some(pitch_deg) = pitch_deg {
+        calibration.pitch_deg = pitch_deg;
+    }
+
+    let camera = Camera::new(calibration.clone())?;
+    let bottom = calibration.height as f32 - 1.0;
+    match camera.pixel_to_ground(calibration.width as f32 / 2.0, bottom)? {
+        Some((_, ahead)) => println!("The bottom of the frame is {:.2} m ahead", ahead),
+        None => println!("The bottom of the frame doesn't see the track, check height and pitch"),
+    }
+
+    println!("{}", serde_json::to_string_pretty(&calibration)?);
+
+    if save {
+        save(&calibration)?;
+    }
+
+    Ok(())
+}
+
+/// Sets the `calibration` section of the config file, leaving the rest of the file as it is.
+fn save(calibration: &CameraCalibration) -> anyhow::Result<()> {
+    let path = config::path();
+    let mut config = if path.exists() {
+        serde_json::from_reader(File::open(&path)?)?
+    } else {
+        Value::Object(Map::new())
+    };
+
+    match &mut config {
+        Value::Object(config) => {
+            config.insert(
+                String::from("calibration"),
+                serde_json::to_value(calibration)?,
+            );
+        }
+        _ => bail!("{} is not a JSON object", path.display()),
+    }
+
+    serde_json::to_writer_pretty(File::create(&path)?, &config)?;
+    println!("Saved to {}", path.display());
+    Ok(())
+}
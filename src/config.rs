@@ -0,0 +1,70 @@
+//! Tuning parameters of the driver.
+//!
+//! Read from the JSON file named by the `CONFIG` environment variable, or `config.json` in the
+//! working directory if it exists. Missing fields keep their defaults, so a config file only
+//! needs the values it changes. `cargo run -- config` prints the effective configuration.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    calibration::CameraCalibration, connection::ConnectionConfig, estimator::EstimatorConfig,
+    frame::FrameConfig, lap::LapConfig, obstacle::ObstacleConfig, odometry::OdometryConfig,
+    preprocess::PreprocessConfig, racing_line::RacingLineConfig, raspi::RaspiConfig,
+    recovery::RecoveryConfig, track_map::TrackMapConfig, turnaround::TurnaroundConfig,
+    vision::VisionParams, watchdog::WatchdogConfig,
+};
+
+const DEFAULT_PATH: &str = "config.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub calibration: Option<CameraCalibration>,
+    pub connection: ConnectionConfig,
+    pub estimator: EstimatorConfig,
+    pub frames: FrameConfig,
+    pub laps: LapConfig,
+    pub obstacles: ObstacleConfig,
+    pub odometry: OdometryConfig,
+    pub preprocess: PreprocessConfig,
+    pub racing_line: RacingLineConfig,
+    pub raspi: RaspiConfig,
+    pub recovery: RecoveryConfig,
+    pub track_map: TrackMapConfig,
+    pub turnaround: TurnaroundConfig,
+    pub vision: VisionParams,
+    pub watchdog: WatchdogConfig,
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Config> {
+        match std::env::var("CONFIG") {
+            Ok(path) => Config::load(Path::new(&path)),
+            Err(_) if Path::new(DEFAULT_PATH).exists() => Config::load(Path::new(DEFAULT_PATH)),
+            Err(_) => Ok(Config::default()),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        let file =
+            File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+        serde_json::from_reader(file).with_context(|| format!("Invalid config {}", path.display()))
+    }
+}
+
+/// The config file that is read, or would be if it existed.
+pub fn path() -> PathBuf {
+    std::env::var("CONFIG").map_or(PathBuf::from(DEFAULT_PATH), PathBuf::from)
+}
+
+pub fn run() -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    println!("{}", serde_json::to_string_pretty(&config)?);
+    Ok(())
+}
@@ -1,17 +1,38 @@
 use std::{
-    io::{Read, Write},
-    net::{SocketAddr, TcpStream},
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
     time::Duration,
 };
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Serialize)]
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, Serialize)]
 #[serde(tag = "action")]
 #[serde(rename_all = "lowercase")]
 pub enum Command {
-    Forward { value: f32 },
-    Turn { value: f32 },
+    Forward {
+        value: f32,
+    },
+    /// Throttle backwards, `value` is positive like for `Forward`.
+    Reverse {
+        value: f32,
+    },
+    Turn {
+        value: f32,
+    },
+}
+
+impl Command {
+    /// Forward for positive `forward` values and reverse for negative ones.
+    pub fn throttle(forward: f32) -> Command {
+        if forward < 0.0 {
+            Command::Reverse { value: -forward }
+        } else {
+            Command::Forward { value: forward }
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -22,37 +43,195 @@ pub struct LoginMessage<'a> {
     pub team_id: &'a str,
 }
 
+/// Length header in front of every image sent by the simulator.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    /// Big-endian u16, limits images to 64 KiB.
+    U16,
+    /// Big-endian u32.
+    U32,
+}
+
+impl Framing {
+    fn header_len(self) -> usize {
+        match self {
+            Framing::U16 => 2,
+            Framing::U32 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    pub framing: Framing,
+    /// Longest image accepted, anything longer is treated as a protocol error.
+    pub max_frame_bytes: usize,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            framing: Framing::U16,
+            max_frame_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
 pub struct Connection {
     tcp_stream: TcpStream,
+    framing: Framing,
+    max_frame_bytes: usize,
 }
 
 impl Connection {
-    pub fn connect(address: &str, login_message: &LoginMessage) -> anyhow::Result<Connection> {
-        let address: SocketAddr = address.parse().unwrap();
-        let tcp_stream = TcpStream::connect_timeout(&address, Duration::from_secs(2));
-        let mut tcp_stream = tcp_stream.expect("Expected Robotini server to answer");
+    pub fn connect(
+        address: &str,
+        login_message: &LoginMessage,
+        config: &ConnectionConfig,
+    ) -> Result<Connection> {
+        let address: SocketAddr = address.parse().map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid address {}: {}", address, error),
+            )
+        })?;
+        let mut tcp_stream = TcpStream::connect_timeout(&address, Duration::from_secs(2))?;
 
         serde_json::to_writer(&mut tcp_stream, login_message)?;
 
         tcp_stream.write_all(&[b'\n'])?;
 
-        Ok(Connection { tcp_stream })
+        Ok(Connection {
+            tcp_stream,
+            framing: config.framing,
+            max_frame_bytes: config.max_frame_bytes,
+        })
     }
 
-    pub fn send(&mut self, command: &Command) -> anyhow::Result<()> {
+    pub fn send(&mut self, command: &Command) -> Result<()> {
         serde_json::to_writer(&mut self.tcp_stream, command)?;
         self.tcp_stream.write_all(&[b'\n'])?;
         Ok(())
     }
 
-    pub fn read_next_image(&mut self) -> anyhow::Result<Vec<u8>> {
-        let mut length = [0u8; 2];
-        self.tcp_stream.read_exact(&mut length)?;
-        let length = u16::from_be_bytes(length) as usize;
+    /// Waits until the next image starts arriving. Returns false if it didn't within `timeout`.
+    pub fn wait_for_image(&mut self, timeout: Duration) -> Result<bool> {
+        self.tcp_stream.set_read_timeout(Some(timeout))?;
+        let result = self.tcp_stream.peek(&mut [0u8; 1]);
+        self.tcp_stream.set_read_timeout(None)?;
+
+        match result {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) => Ok(true),
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn read_next_image(&mut self) -> Result<Vec<u8>> {
+        read_image(&mut self.tcp_stream, self.framing, self.max_frame_bytes)
+    }
+
+    pub fn close(&mut self) {
+        self.tcp_stream.shutdown(Shutdown::Both).ok();
+    }
+}
 
-        let mut buffer = vec![0u8; length];
-        self.tcp_stream.read_exact(&mut buffer)?;
+/// Reads one image with a `framing` length header in front of it.
+fn read_image(reader: &mut impl Read, framing: Framing, max_frame_bytes: usize) -> Result<Vec<u8>> {
+    let mut header = [0u8; 4];
+    let header = &mut header[..framing.header_len()];
+    reader.read_exact(header)?;
+    let length = header
+        .iter()
+        .fold(0usize, |length, byte| length << 8 | *byte as usize);
+
+    if length == 0 {
+        return Err(Error::Protocol(String::from("Received an empty frame")));
+    }
+    if length > max_frame_bytes {
+        return Err(Error::Protocol(format!(
+            "Frame of {} bytes is over the limit of {} bytes, check the framing",
+            length, max_frame_bytes
+        )));
+    }
+
+    let mut buffer = vec![0u8; length];
+    reader.read_exact(&mut buffer)?;
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(header: &[u8], len: usize) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.extend((0..len).map(|i| i as u8));
+        data
+    }
+
+    #[test]
+    fn reads_u16_header() {
+        let data = framed(&[0x01, 0x02], 0x0102);
+        let image = read_image(&mut data.as_slice(), Framing::U16, usize::MAX).unwrap();
+        assert_eq!(image.len(), 0x0102);
+        assert_eq!(image[..3], [0, 1, 2]);
+    }
+
+    #[test]
+    fn reads_u16_header_at_the_limit() {
+        let data = framed(&[0xff, 0xff], 0xffff);
+        let image = read_image(&mut data.as_slice(), Framing::U16, usize::MAX).unwrap();
+        assert_eq!(image.len(), 0xffff);
+    }
+
+    #[test]
+    fn reads_u32_header_over_u16() {
+        let data = framed(&[0x00, 0x01, 0x00, 0x02], 0x0001_0002);
+        let image = read_image(&mut data.as_slice(), Framing::U32, usize::MAX).unwrap();
+        assert_eq!(image.len(), 0x0001_0002);
+    }
+
+    #[test]
+    fn leaves_the_next_image_unread() {
+        let mut data = framed(&[0x00, 0x02], 2);
+        data.extend(framed(&[0x00, 0x03], 3));
+        let mut reader = data.as_slice();
+
+        assert_eq!(read_image(&mut reader, Framing::U16, 16).unwrap(), [0, 1]);
+        assert_eq!(
+            read_image(&mut reader, Framing::U16, 16).unwrap(),
+            [0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn rejects_empty_frame() {
+        let data = [0u8, 0, 0, 0];
+        let error = read_image(&mut &data[..], Framing::U32, 16).unwrap_err();
+        assert!(matches!(error, Error::Protocol(_)));
+    }
+
+    #[test]
+    fn rejects_frame_over_the_limit() {
+        let data = framed(&[0x00, 0x00, 0x01, 0x00], 0x100);
+        let error = read_image(&mut data.as_slice(), Framing::U32, 0xff).unwrap_err();
+        assert!(matches!(error, Error::Protocol(_)));
+    }
 
-        Ok(buffer)
+    #[test]
+    fn truncated_image_is_a_connection_error() {
+        let data = framed(&[0x00, 0x10], 4);
+        let error = read_image(&mut data.as_slice(), Framing::U16, 64).unwrap_err();
+        assert!(matches!(error, Error::Connection(_)));
     }
 }
@@ -0,0 +1,190 @@
+//! Runtime-selectable debug image output.
+//!
+//! `DEBUG_IMAGES` selects which frames get their intermediate images written:
+//!
+//! - `off` (default)
+//! - `every:N` dumps every Nth frame
+//! - `trigger` dumps the next frame after pressing `d` in the preview window or creating a
+//!   `trigger` file in the session directory
+//! - `continuous` dumps every frame
+//!
+//! `RECORD=1` additionally saves every original frame to `<session>/frames` for replay. All
+//! images are numbered by frame and share a disk budget of `DEBUG_BUDGET_MB` megabytes
+//! (default 500), after which writing stops.
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use opencv::{imgcodecs, prelude::*};
+
+use crate::recording;
+
+const DEFAULT_BUDGET_MB: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugMode {
+    Off,
+    EveryNth(usize),
+    OnTrigger,
+    Continuous,
+}
+
+impl FromStr for DebugMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(DebugMode::Off),
+            "trigger" => Ok(DebugMode::OnTrigger),
+            "continuous" => Ok(DebugMode::Continuous),
+            _ => {
+                let n = s
+                    .strip_prefix("every:")
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| anyhow!("Unknown debug image mode {}", s))?;
+                Ok(DebugMode::EveryNth(n))
+            }
+        }
+    }
+}
+
+pub struct DebugOutput {
+    mode: DebugMode,
+    record: bool,
+    dir: PathBuf,
+    frame: usize,
+    dumping: bool,
+    triggered: bool,
+    budget_bytes: u64,
+    used_bytes: u64,
+}
+
+impl DebugOutput {
+    /// Debug output that never writes anything, for tools that only run the pipeline.
+    pub fn off() -> DebugOutput {
+        DebugOutput {
+            mode: DebugMode::Off,
+            record: false,
+            dir: PathBuf::new(),
+            frame: 0,
+            dumping: false,
+            triggered: false,
+            budget_bytes: 0,
+            used_bytes: 0,
+        }
+    }
+
+    pub fn from_env(session_dir: &Path) -> anyhow::Result<DebugOutput> {
+        let mode = match std::env::var("DEBUG_IMAGES") {
+            Ok(mode) => mode.parse()?,
+            Err(_) => DebugMode::Off,
+        };
+
+        let record = std::env::var("RECORD").is_ok_and(|record| record != "0");
+
+        let budget_mb = match std::env::var("DEBUG_BUDGET_MB") {
+            Ok(budget) => budget.parse()?,
+            Err(_) => DEFAULT_BUDGET_MB,
+        };
+
+        if mode != DebugMode::Off {
+            std::fs::create_dir_all(session_dir.join("debug"))?;
+        }
+
+        if record {
+            std::fs::create_dir_all(session_dir.join("frames"))?;
+        }
+
+        Ok(DebugOutput {
+            mode,
+            record,
+            dir: session_dir.to_owned(),
+            frame: 0,
+            dumping: false,
+            triggered: false,
+            budget_bytes: budget_mb * 1024 * 1024,
+            used_bytes: 0,
+        })
+    }
+
+    /// Dumps the next frame when in `trigger` mode.
+    pub fn trigger(&mut self) {
+        self.triggered = true;
+    }
+
+    /// Decides whether the intermediate images of `frame` are written.
+    pub fn begin_frame(&mut self, frame: usize) {
+        self.frame = frame;
+
+        if self.mode == DebugMode::OnTrigger {
+            let trigger_file = self.dir.join("trigger");
+            if trigger_file.exists() {
+                std::fs::remove_file(trigger_file).ok();
+                self.triggered = true;
+            }
+        }
+
+        self.dumping = match self.mode {
+            DebugMode::Off => false,
+            DebugMode::EveryNth(n) => frame % n == 0,
+            DebugMode::OnTrigger => std::mem::replace(&mut self.triggered, false),
+            DebugMode::Continuous => true,
+        };
+    }
+
+    /// Saves the original frame if recording is enabled.
+    pub fn record_frame(&mut self, mat: &Mat) {
+        if !self.record {
+            return;
+        }
+
+        let path = recording::frame_path(&self.dir, self.frame);
+        self.write(&path, mat);
+    }
+
+    /// Saves an intermediate image of the current frame if it's being dumped.
+    pub fn save(&mut self, name: &str, mat: &Mat) {
+        if !self.dumping {
+            return;
+        }
+
+        let path = self
+            .dir
+            .join("debug")
+            .join(format!("frame{:05}-{}.png", self.frame, name));
+        self.write(&path, mat);
+    }
+
+    /// Writing debug images is best effort, a failure is reported but never stops the driver.
+    fn write(&mut self, path: &Path, mat: &Mat) {
+        if self.used_bytes >= self.budget_bytes {
+            return;
+        }
+
+        if let Err(error) = self.try_write(path, mat) {
+            log_error!("Could not write {}: {}", path.display(), error);
+        }
+    }
+
+    fn try_write(&mut self, path: &Path, mat: &Mat) -> anyhow::Result<()> {
+        imgcodecs::imwrite(
+            &path.to_string_lossy(),
+            mat,
+            &opencv::core::Vector::<i32>::new(),
+        )?;
+        self.used_bytes += std::fs::metadata(path)?.len();
+
+        if self.used_bytes >= self.budget_bytes {
+            log!(
+                "Debug image budget of {} MB used up, no more images will be written",
+                self.budget_bytes / 1024 / 1024
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,608 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use anyhow::bail;
+use opencv::{highgui, prelude::*};
+
+use crate::{
+    backend::Backend,
+    calibration::Camera,
+    config::Config,
+    connection::{Command, LoginMessage},
+    debug::DebugOutput,
+    error,
+    estimator::{fit_lane, Lane, LaneFilter},
+    frame::{self, FramePolicy, ReceivedFrame},
+    lap::{LapCounter, LapEvent},
+    obstacle::{Obstacle, ObstacleDetector},
+    odometry::{Odometry, Pose},
+    overlay,
+    preprocess::preprocess,
+    racing_line::RacingLine,
+    recovery::{Recovery, RecoveryPhase},
+    stream::TelemetryServer,
+    telemetry::{self, Telemetry, TelemetryRecord},
+    track_map::TrackMap,
+    turnaround::{Turnaround, TurnaroundLeg},
+    vision::{
+        find_horizon, find_track_edges, measure, process_frame, roi_below, ChannelFrames,
+        Measurements,
+    },
+    watchdog::Watchdog,
+};
+
+pub fn create_session_dir() -> anyhow::Result<PathBuf> {
+    let dir = match std::env::var("SESSION_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => Path::new("captures").join(format!("session-{}", telemetry::unix_time_ms())),
+    };
+
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// What sets a car apart from the others driven by the same process.
+pub struct CarSetup {
+    pub name: String,
+    pub color: String,
+    pub team_id: String,
+    /// Where the car's telemetry, recordings and debug images are written.
+    pub session_dir: PathBuf,
+    pub gui: bool,
+    pub telemetry_server: Option<TelemetryServer>,
+}
+
+pub struct Driver {
+    config: Config,
+    connection: Backend,
+    camera: Option<Camera>,
+    car_state: CarState,
+    debug: DebugOutput,
+    telemetry: Telemetry,
+    telemetry_server: Option<TelemetryServer>,
+    watchdog: Watchdog,
+    gui: bool,
+    frame_i: usize,
+    frame_errors: usize,
+    last_good_frame: Option<Mat>,
+    reused_in_row: usize,
+    frames_reused: usize,
+    stopped: bool,
+}
+
+impl Driver {
+    /// Starts the car configured by the environment.
+    pub fn start() -> anyhow::Result<Driver> {
+        let config = Config::from_env()?;
+        let session_dir = create_session_dir()?;
+        log!("Session directory is {}", session_dir.display());
+
+        Driver::new(
+            config,
+            CarSetup {
+                name: String::from("Team Rust"),
+                color: String::from("#ff9514"),
+                team_id: std::env::var("teamid").unwrap_or(String::from("rust")),
+                session_dir,
+                gui: std::env::var("DEBUG_GUI").is_ok_and(|gui| gui != "0"),
+                telemetry_server: TelemetryServer::from_env()?,
+            },
+        )
+    }
+
+    pub fn new(config: Config, setup: CarSetup) -> anyhow::Result<Driver> {
+        let CarSetup {
+            name,
+            color,
+            team_id,
+            session_dir,
+            gui,
+            telemetry_server,
+        } = setup;
+        let frame_size = config.preprocess.output_size(&config.frames)?;
+        if let Some(calibration) = &config.calibration {
+            if calibration.width != frame_size.width || calibration.height != frame_size.height {
+                bail!(
+                    "The camera calibration is for {}x{} frames, but the preprocessed frames are {}x{}",
+                    calibration.width,
+                    calibration.height,
+                    frame_size.width,
+                    frame_size.height
+                );
+            }
+        }
+        let debug = DebugOutput::from_env(&session_dir)?;
+
+        let connection = Backend::from_env(
+            &config,
+            &LoginMessage {
+                name: &name,
+                color: &color,
+                team_id: &team_id,
+            },
+        )?;
+
+        if gui {
+            let window = "robotini";
+            highgui::named_window(window, 1)?;
+        }
+
+        let car_state = CarState {
+            speed: 0.0,
+            wheels_turn: 0.0,
+            previous_horizons: VecDeque::new(),
+            lane_filter: LaneFilter::new(config.estimator.clone()),
+            odometry: Odometry::new(config.odometry.clone()),
+            track_map: TrackMap::new(config.track_map.clone())?,
+            racing_line: RacingLine::from_config(&config.racing_line)?,
+            obstacles: ObstacleDetector::new(config.obstacles.clone())?,
+            laps: LapCounter::new(config.laps.clone())?,
+            recovery: Recovery::new(config.recovery.clone()),
+            turnaround: Turnaround::new(config.turnaround.clone()),
+            last_forward: 0.0,
+            last_turn: 0.0,
+        };
+
+        if let (Some(line), Some(profile)) = (&car_state.racing_line, car_state.track_map.profile())
+        {
+            line.check_track(profile)?;
+        }
+
+        let camera = config.calibration.clone().map(Camera::new).transpose()?;
+        let telemetry = Telemetry::from_env(&session_dir)?;
+        let watchdog = Watchdog::new(config.watchdog.clone());
+
+        Ok(Driver {
+            config,
+            connection,
+            camera,
+            car_state,
+            debug,
+            telemetry,
+            telemetry_server,
+            watchdog,
+            gui,
+            frame_i: 0,
+            frame_errors: 0,
+            last_good_frame: None,
+            reused_in_row: 0,
+            frames_reused: 0,
+            stopped: false,
+        })
+    }
+
+    /// Drives with `run` and then shuts down whether it succeeded or not.
+    pub fn drive(&mut self, running: &AtomicBool) -> anyhow::Result<()> {
+        let result = self.run(running);
+
+        if let Err(error) = &result {
+            log_error!("Driver failed: {}", error);
+        }
+
+        let shutdown_result = self.shutdown();
+        result.and(shutdown_result)
+    }
+
+    /// Drives until `running` is cleared, the preview window is closed with a key press or an
+    /// error occurs. The car is left moving, call `shutdown` afterwards.
+    pub fn run(&mut self, running: &AtomicBool) -> anyhow::Result<()> {
+        while running.load(Ordering::SeqCst) {
+            if !self.step()? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Processes one frame. Returns false if the user asked to quit.
+    ///
+    /// Bad frames are handled according to `FrameConfig::policy` and a frame that can't be
+    /// processed is skipped, both are counted in `frame_errors`. A skipped frame leaves the car
+    /// with its previous commands unless the watchdog steps in.
+    fn step(&mut self) -> anyhow::Result<bool> {
+        let frame_i = self.frame_i;
+
+        let frame_start = Instant::now();
+        if !self
+            .connection
+            .wait_for_image(self.watchdog.frame_timeout())?
+        {
+            self.watchdog.frame_missing();
+            self.send_safe_output()?;
+            return Ok(true);
+        }
+
+        let image = self.connection.read_next_image()?;
+        let read_time = frame_start.elapsed();
+
+        let frame = match self.accept_frame(image)? {
+            Some(frame) => frame,
+            None => {
+                self.frame_i += 1;
+                return Ok(true);
+            }
+        };
+        let decode_time = frame_start.elapsed() - read_time;
+
+        let connection = &mut self.connection;
+        let car_state = &mut self.car_state;
+        let debug = &mut self.debug;
+
+        debug.begin_frame(frame_i);
+        debug.record_frame(&frame);
+        let camera = self.camera.as_ref();
+        let update = match frame_update(&frame, car_state, &self.config, camera, debug) {
+            Ok(update) => update,
+            Err(error) if error.is_frame_error() => {
+                log_error!("Skipping frame {}: {}", frame_i, error);
+                self.frame_errors += 1;
+                self.frame_i += 1;
+                self.skip_frame()?;
+                return Ok(true);
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let maneuver = update.recovery.is_some() || update.turnaround.is_some();
+        let fault = if maneuver {
+            self.watchdog.check_maneuver(update.forward, update.turn)
+        } else {
+            self.watchdog
+                .check(&update.measurements, update.forward, update.turn)
+        };
+        let (forward, turn) = match fault {
+            None => {
+                // The speed estimate would briefly drive forward during a maneuver or reversing.
+                if !maneuver && update.forward >= 0.0 {
+                    connection.send(&Command::Forward {
+                        value: update.speed,
+                    })?;
+                }
+                (update.forward, update.turn)
+            }
+            Some(_) => self.watchdog.safe_output(),
+        };
+
+        connection.send(&Command::Turn { value: turn })?;
+        connection.send(&Command::throttle(forward))?;
+        car_state.last_forward = forward;
+        car_state.last_turn = turn;
+        let update_time = frame_start.elapsed() - read_time - decode_time;
+
+        let record = TelemetryRecord {
+            frame: frame_i,
+            time_ms: telemetry::unix_time_ms(),
+            horizon: update.horizon,
+            horizon_interpolated: update.horizon_interpolated,
+            blue_ratio: update.measurements.blue_ratio,
+            green_ratio: update.measurements.green_ratio,
+            red_ratio: update.measurements.red_ratio,
+            speed: car_state.speed,
+            wheels_turn: car_state.wheels_turn,
+            forward,
+            turn,
+            read_ms: read_time.as_secs_f32() * 1000.0,
+            decode_ms: decode_time.as_secs_f32() * 1000.0,
+            update_ms: update_time.as_secs_f32() * 1000.0,
+            fault,
+            frame_errors: self.frame_errors,
+            frames_reused: self.frames_reused,
+            lane_detected: update.lane_detected,
+            lane_offset: update.lane.offset,
+            lane_heading: update.lane.heading,
+            lane_curvature: update.lane.curvature,
+            distance: update.pose.distance,
+            position_x: update.pose.x,
+            position_y: update.pose.y,
+            heading: update.pose.heading,
+            lap_progress: update.lap_progress,
+            track_position: update.track_position,
+            obstacles: update.obstacles.len(),
+            obstacle_position: update.obstacles.first().map(|obstacle| obstacle.position),
+            obstacle_closeness: update.obstacles.first().map(|obstacle| obstacle.closeness),
+            obstacle_distance: update
+                .obstacles
+                .first()
+                .and_then(|obstacle| obstacle.distance),
+            laps: car_state.laps.laps(),
+            lap_time: update.lap.map(|lap| lap.time.as_secs_f32()),
+            recovery: update.recovery,
+            wrong_way: car_state.turnaround.wrong_way(),
+            turnaround: update.turnaround,
+        };
+        self.telemetry.log(&record)?;
+
+        let streaming = self
+            .telemetry_server
+            .as_ref()
+            .is_some_and(TelemetryServer::has_clients);
+
+        if self.gui || streaming {
+            let mut viz_frame = overlay::render(&frame, &update.frames, &record)?;
+            overlay::draw_obstacles(&mut viz_frame, &update.obstacles)?;
+
+            if let Some(server) = &self.telemetry_server {
+                server.send(&record, &viz_frame);
+            }
+
+            if self.gui {
+                highgui::imshow("robotini", &viz_frame)?;
+            }
+        }
+
+        self.frame_i += 1;
+
+        if self.gui {
+            let key = highgui::wait_key(10)?;
+            if key == 'd' as i32 {
+                self.debug.trigger();
+            } else if key > 0 && key != 255 {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Decodes, validates, preprocesses and undistorts a received frame. Returns the frame to
+    /// process, which is the last good one if the policy reuses it, or None if the frame is
+    /// skipped.
+    fn accept_frame(&mut self, image: ReceivedFrame) -> anyhow::Result<Option<Mat>> {
+        let frame_config = &self.config.frames;
+        let preprocess_config = &self.config.preprocess;
+        let camera = &self.camera;
+        let error = match frame::decode(image, frame_config).and_then(|frame| {
+            frame::validate(&frame, frame_config)?;
+            let frame = preprocess(frame, preprocess_config)?;
+            match camera {
+                Some(camera) => camera.undistort(frame),
+                None => Ok(frame),
+            }
+        }) {
+            Ok(frame) => {
+                self.reused_in_row = 0;
+                self.last_good_frame = Some(frame.clone());
+                return Ok(Some(frame));
+            }
+            Err(error) => error,
+        };
+
+        log_error!("Bad frame {}: {}", self.frame_i, error);
+        self.frame_errors += 1;
+
+        match frame_config.policy {
+            FramePolicy::Stop => Err(error.into()),
+            FramePolicy::Reuse
+                if self.last_good_frame.is_some()
+                    && self.reused_in_row < frame_config.max_reused =>
+            {
+                self.reused_in_row += 1;
+                self.frames_reused += 1;
+                Ok(self.last_good_frame.clone())
+            }
+            FramePolicy::Reuse | FramePolicy::Skip => {
+                self.skip_frame()?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn skip_frame(&mut self) -> anyhow::Result<()> {
+        if self.watchdog.frame_skipped().is_some() {
+            self.send_safe_output()?;
+        }
+        Ok(())
+    }
+
+    fn send_safe_output(&mut self) -> anyhow::Result<()> {
+        let (forward, turn) = self.watchdog.safe_output();
+        self.connection.send(&Command::Turn { value: turn })?;
+        self.connection.send(&Command::throttle(forward))?;
+        Ok(())
+    }
+
+    /// Stops the car, flushes the logs and closes the connection.
+    pub fn shutdown(&mut self) -> anyhow::Result<()> {
+        let stop_result = self.stop_car();
+        let flush_result = self.telemetry.flush();
+        self.connection.close();
+
+        stop_result.and(flush_result)
+    }
+
+    fn stop_car(&mut self) -> anyhow::Result<()> {
+        if self.stopped {
+            return Ok(());
+        }
+        self.stopped = true;
+
+        log!("Stopping the car");
+        self.connection.send(&Command::Forward { value: 0.0 })?;
+        self.connection.send(&Command::Turn { value: 0.0 })?;
+        Ok(())
+    }
+}
+
+impl Drop for Driver {
+    /// Last resort for when the driver is unwound by a panic before `shutdown` is called.
+    fn drop(&mut self) {
+        if let Err(error) = self.stop_car() {
+            log_error!("Failed to stop the car: {}", error);
+        }
+    }
+}
+
+/// Forward value sent while driving normally.
+const CRUISE_FORWARD: f32 = 0.15;
+
+struct CarState {
+    wheels_turn: f32,
+    speed: f32,
+    previous_horizons: VecDeque<i32>,
+    lane_filter: LaneFilter,
+    odometry: Odometry,
+    track_map: TrackMap,
+    racing_line: Option<RacingLine>,
+    obstacles: ObstacleDetector,
+    laps: LapCounter,
+    recovery: Recovery,
+    turnaround: Turnaround,
+    /// Commands sent for the previous frame, the control input of `lane_filter` and
+    /// `odometry`.
+    last_forward: f32,
+    last_turn: f32,
+}
+
+struct FrameUpdate {
+    frames: Vec<ChannelFrames>,
+    horizon: i32,
+    measurements: Measurements,
+    horizon_interpolated: i32,
+    lane: Lane,
+    lane_detected: bool,
+    pose: Pose,
+    lap_progress: Option<f32>,
+    track_position: Option<f32>,
+    /// Nearest first.
+    obstacles: Vec<Obstacle>,
+    /// Lap completed on this frame.
+    lap: Option<LapEvent>,
+    /// Phase of the off-track recovery that chose the commands, None for normal control.
+    recovery: Option<RecoveryPhase>,
+    /// Leg of the turnaround that chose the commands when driving the wrong way.
+    turnaround: Option<TurnaroundLeg>,
+    speed: f32,
+    forward: f32,
+    turn: f32,
+}
+
+fn frame_update(
+    frame: &Mat,
+    state: &mut CarState,
+    config: &Config,
+    camera: Option<&Camera>,
+    debug: &mut DebugOutput,
+) -> error::Result<FrameUpdate> {
+    let wheels_turn = &mut state.wheels_turn;
+    let speed = &mut state.speed;
+
+    let frames = process_frame(frame, &config.vision, debug)?;
+    let horizon_i = find_horizon(&frames)?;
+
+    state.previous_horizons.push_front(horizon_i);
+    state.previous_horizons.truncate(60);
+    let horizon_interpolated = (state.previous_horizons.iter().sum::<i32>() as f32
+        / state.previous_horizons.len() as f32) as i32;
+
+    let measurements = measure(&frames, horizon_interpolated, debug)?;
+    let Measurements {
+        blue_ratio,
+        green_ratio,
+        red_ratio,
+        ..
+    } = measurements;
+
+    let rows = find_track_edges(&frames, roi_below(&frames, horizon_interpolated), 4);
+    let measured_lane = fit_lane(&rows, frame.cols(), frame.rows());
+    // Reversing turns the car the other way for the same wheel angle.
+    let steering = if state.last_forward < 0.0 {
+        -state.last_turn
+    } else {
+        state.last_turn
+    };
+    state.lane_filter.predict(steering);
+    if let Some(lane) = measured_lane {
+        state.lane_filter.update(lane);
+    }
+
+    if config.estimator.steer {
+        *wheels_turn = state.lane_filter.steering().clamp(-0.9f32, 0.9f32);
+    } else {
+        let diff = red_ratio - green_ratio;
+        if blue_ratio < 0.6 {
+            *wheels_turn = (*wheels_turn - diff * 1.8f32).clamp(-0.9f32, 0.9f32);
+        }
+    }
+
+    let pose = state
+        .odometry
+        .update(frame, state.last_forward, state.last_turn)?;
+    let planned_forward = state.track_map.update(pose).unwrap_or(CRUISE_FORWARD);
+    let mut forward = match state.odometry.upcoming_corner() {
+        Some(corner) => planned_forward.min(corner.max_forward),
+        None => planned_forward,
+    };
+
+    let track_position = state.track_map.position(&pose);
+    if let (Some(line), Some(position)) = (&state.racing_line, track_position) {
+        let lane = state.lane_filter.estimate();
+        let (line_forward, line_turn) = line.follow(position, &lane, &config.racing_line);
+        *wheels_turn = line_turn.clamp(-0.9f32, 0.9f32);
+        forward = line_forward;
+    }
+
+    let obstacles = state
+        .obstacles
+        .detect(frame, &frames, horizon_interpolated, camera, debug)?;
+    let obstacle_config = state.obstacles.config();
+    if let Some(nearest) = obstacles.first().filter(|_| obstacle_config.avoid) {
+        let (avoid_forward, avoid_turn) = nearest.avoid(forward, *wheels_turn, obstacle_config);
+        *wheels_turn = avoid_turn.clamp(-0.9f32, 0.9f32);
+        forward = avoid_forward;
+    }
+
+    let now = Instant::now();
+    let lap = state.laps.update(frame, now)?;
+
+    let max_speed = 0.03;
+    let min_speed = 0.002;
+    *speed = (0.001 / wheels_turn.abs().max(0.01))
+        .min(max_speed)
+        .max(min_speed);
+
+    let speed = *speed;
+    let mut turn = *wheels_turn;
+
+    *wheels_turn *= 0.3;
+
+    let detected_lane = measured_lane.map(|_| state.lane_filter.estimate());
+    let recovery_output =
+        state
+            .recovery
+            .update(frame, &measurements, detected_lane, state.last_forward, now)?;
+    // Turning around is left for after a recovery, which has the track in sight again.
+    let maneuver = match recovery_output {
+        Some(output) => Some(output),
+        None => state
+            .turnaround
+            .update(&rows, state.lane_filter.estimate(), now),
+    };
+    if let Some((maneuver_forward, maneuver_turn)) = maneuver {
+        forward = maneuver_forward;
+        turn = maneuver_turn;
+    }
+
+    Ok(FrameUpdate {
+        frames,
+        horizon: horizon_i,
+        measurements,
+        horizon_interpolated,
+        lane: state.lane_filter.estimate(),
+        lane_detected: measured_lane.is_some(),
+        pose,
+        lap_progress: state.odometry.lap_progress(),
+        track_position,
+        obstacles,
+        lap,
+        recovery: state.recovery.phase(),
+        turnaround: state.turnaround.leg(),
+        speed,
+        forward,
+        turn,
+    })
+}
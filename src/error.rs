@@ -0,0 +1,72 @@
+//! Errors of the driving pipeline.
+//!
+//! Connection and protocol errors end the run, decode, OpenCV and vision errors only concern a
+//! single frame, which the driver skips.
+
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The server couldn't be reached or the connection broke.
+    Connection(io::Error),
+    /// The server sent something that doesn't follow the protocol.
+    Protocol(String),
+    /// A frame couldn't be decoded into an image.
+    Decode(String),
+    /// An OpenCV call failed while processing a frame.
+    OpenCv(opencv::Error),
+    /// A frame couldn't be processed, such as when no horizon could be found in it.
+    Vision(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Errors caused by a single bad frame. The driver skips the frame and carries on.
+    pub fn is_frame_error(&self) -> bool {
+        match self {
+            Error::Decode(_) | Error::OpenCv(_) | Error::Vision(_) => true,
+            Error::Connection(_) | Error::Protocol(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Connection(error) => write!(f, "Connection error: {}", error),
+            Error::Protocol(message) => write!(f, "Protocol error: {}", message),
+            Error::Decode(message) => write!(f, "Could not decode frame: {}", message),
+            Error::OpenCv(error) => write!(f, "OpenCV error: {}", error),
+            Error::Vision(message) => write!(f, "Vision error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connection(error) => Some(error),
+            Error::OpenCv(error) => Some(error),
+            Error::Protocol(_) | Error::Decode(_) | Error::Vision(_) => None,
+        }
+    }
+}
+
+impl From<opencv::Error> for Error {
+    fn from(error: opencv::Error) -> Self {
+        Error::OpenCv(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Connection(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Connection(error.into())
+    }
+}
@@ -0,0 +1,400 @@
+//! Kalman filter over the lane the car is driving in.
+//!
+//! The state is the lateral offset, heading and curvature of the lane's centerline in image
+//! units: offset is the distance of the centerline from the middle of the bottom row in half
+//! frame widths, positive to the right, heading its change per frame height up the image and
+//! curvature the change of heading. Every frame the state is first predicted with the previous
+//! turn command and then corrected with the centerline fitted to `find_track_edges`, so a frame
+//! without detections coasts on the prediction.
+
+use serde::{Deserialize, Serialize};
+
+use crate::vision::TrackRow;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Lane {
+    pub offset: f32,
+    pub heading: f32,
+    pub curvature: f32,
+}
+
+impl Lane {
+    fn to_array(self) -> [f32; 3] {
+        [self.offset, self.heading, self.curvature]
+    }
+
+    fn from_array(state: [f32; 3]) -> Lane {
+        Lane {
+            offset: state[0],
+            heading: state[1],
+            curvature: state[2],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EstimatorConfig {
+    /// Variance added to offset, heading and curvature every frame.
+    pub process_noise: [f32; 3],
+    /// Variance of the fitted offset, heading and curvature.
+    pub measurement_noise: [f32; 3],
+    /// Offset change per frame caused by the heading.
+    pub heading_rate: f32,
+    /// Heading change per frame caused by the curvature.
+    pub curvature_rate: f32,
+    /// Heading change per frame caused by the turn command.
+    pub steering_rate: f32,
+    /// Steer from the estimate instead of the line pixel ratios.
+    pub steer: bool,
+    pub offset_gain: f32,
+    pub heading_gain: f32,
+}
+
+impl Default for EstimatorConfig {
+    fn default() -> Self {
+        EstimatorConfig {
+            process_noise: [0.002, 0.005, 0.01],
+            measurement_noise: [0.01, 0.05, 0.2],
+            heading_rate: 0.1,
+            curvature_rate: 0.1,
+            steering_rate: 0.2,
+            steer: false,
+            offset_gain: 0.8,
+            heading_gain: 0.4,
+        }
+    }
+}
+
+pub struct LaneFilter {
+    config: EstimatorConfig,
+    state: [f32; 3],
+    covariance: Matrix,
+}
+
+impl LaneFilter {
+    pub fn new(config: EstimatorConfig) -> LaneFilter {
+        LaneFilter {
+            config,
+            state: [0.0; 3],
+            // Nothing is known before the first frame, let it take over the state completely.
+            covariance: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+
+    /// Advances the state by one frame driven with `turn`.
+    pub fn predict(&mut self, turn: f32) {
+        let config = &self.config;
+        let transition = [
+            [1.0, config.heading_rate, 0.0],
+            [0.0, 1.0, config.curvature_rate],
+            [0.0, 0.0, 1.0],
+        ];
+
+        let mut state = multiply_vector(&transition, &self.state);
+        state[1] -= config.steering_rate * turn;
+        self.state = state;
+
+        let mut covariance = multiply(
+            &multiply(&transition, &self.covariance),
+            &transpose(&transition),
+        );
+        for (i, noise) in config.process_noise.iter().enumerate() {
+            covariance[i][i] += noise;
+        }
+        self.covariance = covariance;
+    }
+
+    /// Corrects the state with a lane fitted to the current frame.
+    pub fn update(&mut self, measured: Lane) {
+        // Every component is measured directly with independent noise, so the update can be
+        // done one component at a time without inverting a matrix.
+        for (i, value) in measured.to_array().iter().enumerate() {
+            let covariance = &self.covariance;
+            let innovation_variance = covariance[i][i] + self.config.measurement_noise[i];
+            let gain = [
+                covariance[0][i] / innovation_variance,
+                covariance[1][i] / innovation_variance,
+                covariance[2][i] / innovation_variance,
+            ];
+
+            let innovation = value - self.state[i];
+            let measured_row = covariance[i];
+            let rows = self.state.iter_mut().zip(self.covariance.iter_mut());
+            for ((state, covariance_row), gain) in rows.zip(&gain) {
+                *state += gain * innovation;
+                for (covariance, measured) in covariance_row.iter_mut().zip(&measured_row) {
+                    *covariance -= gain * measured;
+                }
+            }
+        }
+    }
+
+    pub fn estimate(&self) -> Lane {
+        Lane::from_array(self.state)
+    }
+
+    /// Turn command that steers towards the estimated centerline.
+    pub fn steering(&self) -> f32 {
+        let lane = self.estimate();
+        self.config.offset_gain * lane.offset + self.config.heading_gain * lane.heading
+    }
+}
+
+/// Fits a parabola to the centerline of `rows`, which are taken from a frame of `width` by
+/// `height` pixels. Needs at least two rows with both lines visible.
+pub fn fit_lane(rows: &[TrackRow], width: i32, height: i32) -> Option<Lane> {
+    let half_width = width as f32 / 2.0;
+    let points: Vec<(f32, f32)> = rows
+        .iter()
+        .filter_map(|row| {
+            let center = row.center()?;
+            let up = (height - 1 - row.y) as f32 / height as f32;
+            Some((up, (center as f32 - half_width) / half_width))
+        })
+        .collect();
+
+    let coefficients = match points.len() {
+        0 | 1 => return None,
+        2 => {
+            let (t0, x0) = points[0];
+            let (t1, x1) = points[1];
+            if (t1 - t0).abs() < f32::EPSILON {
+                return None;
+            }
+            let slope = (x1 - x0) / (t1 - t0);
+            [x0 - slope * t0, slope, 0.0]
+        }
+        _ => {
+            // Least squares through the normal equations of x = a + b t + c t².
+            let mut sums = [0.0f32; 5];
+            let mut rhs = [0.0f32; 3];
+            for (t, x) in &points {
+                let mut power = 1.0;
+                for (i, sum) in sums.iter_mut().enumerate() {
+                    *sum += power;
+                    if i < 3 {
+                        rhs[i] += power * x;
+                    }
+                    power *= t;
+                }
+            }
+
+            let normal = [
+                [sums[0], sums[1], sums[2]],
+                [sums[1], sums[2], sums[3]],
+                [sums[2], sums[3], sums[4]],
+            ];
+            solve(&normal, &rhs)?
+        }
+    };
+
+    Some(Lane {
+        offset: coefficients[0],
+        heading: coefficients[1],
+        curvature: 2.0 * coefficients[2],
+    })
+}
+
+type Matrix = [[f32; 3]; 3];
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = [[0.0; 3]; 3];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    result
+}
+
+fn multiply_vector(a: &Matrix, v: &[f32; 3]) -> [f32; 3] {
+    let mut result = [0.0; 3];
+    for (i, value) in result.iter_mut().enumerate() {
+        *value = (0..3).map(|k| a[i][k] * v[k]).sum();
+    }
+    result
+}
+
+fn transpose(a: &Matrix) -> Matrix {
+    let mut result = [[0.0; 3]; 3];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = a[j][i];
+        }
+    }
+    result
+}
+
+fn determinant(a: &Matrix) -> f32 {
+    a[0][0] * (a[1][1] * a[2][2] - a[1][2] * a[2][1])
+        - a[0][1] * (a[1][0] * a[2][2] - a[1][2] * a[2][0])
+        + a[0][2] * (a[1][0] * a[2][1] - a[1][1] * a[2][0])
+}
+
+/// Solves `a x = b` with Cramer's rule, None if `a` is singular.
+fn solve(a: &Matrix, b: &[f32; 3]) -> Option<[f32; 3]> {
+    let det = determinant(a);
+    if det.abs() < 1e-9 {
+        return None;
+    }
+
+    let mut x = [0.0; 3];
+    for (column, value) in x.iter_mut().enumerate() {
+        let mut replaced = *a;
+        for (row, b) in b.iter().enumerate() {
+            replaced[row][column] = *b;
+        }
+        *value = determinant(&replaced) / det;
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(y: i32, center: i32) -> TrackRow {
+        TrackRow {
+            y,
+            green: Some(center),
+            red: Some(center),
+        }
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "{} is not close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn fit_lane_needs_two_rows_with_both_lines() {
+        assert!(fit_lane(&[], 100, 100).is_none());
+        assert!(fit_lane(&[row(99, 50)], 100, 100).is_none());
+
+        let one_line = TrackRow {
+            y: 49,
+            green: Some(60),
+            red: None,
+        };
+        assert!(fit_lane(&[row(99, 50), one_line], 100, 100).is_none());
+    }
+
+    #[test]
+    fn fit_lane_through_two_rows_is_straight() {
+        // The bottom row is centered, halfway up the centerline is a quarter frame to the right.
+        let lane = fit_lane(&[row(99, 50), row(49, 75)], 100, 100).unwrap();
+        assert_close(lane.offset, 0.0);
+        assert_close(lane.heading, 1.0);
+        assert_close(lane.curvature, 0.0);
+    }
+
+    #[test]
+    fn fit_lane_through_two_rows_on_the_same_line_fails() {
+        assert!(fit_lane(&[row(49, 50), row(49, 75)], 100, 100).is_none());
+    }
+
+    #[test]
+    fn fit_lane_recovers_a_parabola() {
+        // x = 0.1 + 0.2 t + 0.4 t², sampled at t = 0, 0.25, 0.5 and 0.75.
+        let rows = [
+            row(999, 1100),
+            row(749, 1175),
+            row(499, 1300),
+            row(249, 1475),
+        ];
+        let lane = fit_lane(&rows, 2000, 1000).unwrap();
+        assert_close(lane.offset, 0.1);
+        assert_close(lane.heading, 0.2);
+        assert_close(lane.curvature, 0.8);
+    }
+
+    #[test]
+    fn fit_lane_with_singular_normal_equations_fails() {
+        let rows = [row(49, 50), row(49, 60), row(49, 70)];
+        assert!(fit_lane(&rows, 100, 100).is_none());
+    }
+
+    #[test]
+    fn update_without_measurement_noise_takes_the_measurement() {
+        let mut filter = LaneFilter::new(EstimatorConfig {
+            measurement_noise: [0.0; 3],
+            ..EstimatorConfig::default()
+        });
+        filter.update(Lane {
+            offset: 0.3,
+            heading: -0.2,
+            curvature: 0.1,
+        });
+
+        let lane = filter.estimate();
+        assert_close(lane.offset, 0.3);
+        assert_close(lane.heading, -0.2);
+        assert_close(lane.curvature, 0.1);
+    }
+
+    #[test]
+    fn update_weighs_the_measurement_by_its_noise() {
+        let config = EstimatorConfig {
+            measurement_noise: [1.0, 3.0, 1.0],
+            ..EstimatorConfig::default()
+        };
+        let mut filter = LaneFilter::new(config);
+        filter.update(Lane {
+            offset: 1.0,
+            heading: 1.0,
+            curvature: 0.0,
+        });
+
+        // Prior variance 1 against measurement variance 1 and 3.
+        let lane = filter.estimate();
+        assert_close(lane.offset, 0.5);
+        assert_close(lane.heading, 0.25);
+        assert_close(lane.curvature, 0.0);
+    }
+
+    #[test]
+    fn repeated_updates_converge_and_shrink_the_covariance() {
+        let mut filter = LaneFilter::new(EstimatorConfig::default());
+        let measured = Lane {
+            offset: -0.4,
+            heading: 0.1,
+            curvature: 0.05,
+        };
+
+        for _ in 0..50 {
+            filter.update(measured);
+        }
+
+        let lane = filter.estimate();
+        assert_close(lane.offset, measured.offset);
+        assert_close(lane.heading, measured.heading);
+        assert_close(lane.curvature, measured.curvature);
+        for i in 0..3 {
+            assert!(filter.covariance[i][i] < 0.01);
+        }
+    }
+
+    #[test]
+    fn predict_coasts_on_heading_and_turn() {
+        let mut filter = LaneFilter::new(EstimatorConfig {
+            measurement_noise: [0.0; 3],
+            ..EstimatorConfig::default()
+        });
+        filter.update(Lane {
+            offset: 0.0,
+            heading: 0.5,
+            curvature: 0.0,
+        });
+        filter.predict(0.5);
+
+        let config = EstimatorConfig::default();
+        let lane = filter.estimate();
+        assert_close(lane.offset, 0.5 * config.heading_rate);
+        assert_close(lane.heading, 0.5 - 0.5 * config.steering_rate);
+    }
+}
@@ -0,0 +1,126 @@
+//! Renders a recorded session into an annotated video.
+//!
+//! `cargo run -- export <session dir> [output] [--fps N]`
+//!
+//! The output defaults to `annotated.avi` in the session directory. An output path without an
+//! `.avi` or `.mp4` extension is treated as a directory and gets numbered PNG frames instead.
+//! Masks are recomputed from the recorded frames, so the overlay reflects the current pipeline.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use opencv::{core::Size, imgcodecs, prelude::*, videoio::VideoWriter};
+
+use crate::{
+    config::Config, debug::DebugOutput, overlay, recording::Recording, vision::process_frame,
+};
+
+const DEFAULT_FPS: f64 = 20.0;
+
+enum Output {
+    Video(PathBuf, Option<VideoWriter>),
+    Images(PathBuf),
+}
+
+impl Output {
+    fn write(&mut self, frame: usize, image: &Mat, fps: f64) -> anyhow::Result<()> {
+        match self {
+            Output::Video(path, writer) => {
+                if writer.is_none() {
+                    let fourcc = match path.extension().and_then(|ext| ext.to_str()) {
+                        Some("mp4") => {
+                            VideoWriter::fourcc(b'm' as i8, b'p' as i8, b'4' as i8, b'v' as i8)?
+                        }
+                        _ => VideoWriter::fourcc(b'M' as i8, b'J' as i8, b'P' as i8, b'G' as i8)?,
+                    };
+                    let size = Size {
+                        width: image.cols(),
+                        height: image.rows(),
+                    };
+                    let video = VideoWriter::new(&path.to_string_lossy(), fourcc, fps, size, true)?;
+                    if !video.is_opened()? {
+                        bail!("Could not open {} for writing", path.display());
+                    }
+                    *writer = Some(video);
+                }
+
+                writer.as_mut().unwrap().write(image)?;
+            }
+            Output::Images(dir) => {
+                let path = dir.join(format!("frame{:05}.png", frame));
+                imgcodecs::imwrite(
+                    &path.to_string_lossy(),
+                    image,
+                    &opencv::core::Vector::<i32>::new(),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let mut positional = Vec::new();
+    let mut fps = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fps" => {
+                let value = args.next().ok_or_else(|| anyhow!("--fps needs a value"))?;
+                fps = Some(value.parse()?);
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let session_dir = match positional.first() {
+        Some(dir) => Path::new(dir.as_str()),
+        None => bail!("Usage: export <session dir> [output] [--fps N]"),
+    };
+
+    let output_path = match positional.get(1) {
+        Some(path) => PathBuf::from(path.as_str()),
+        None => session_dir.join("annotated.avi"),
+    };
+
+    let recording = Recording::open(session_dir)?;
+    let fps = fps.or_else(|| recording.fps()).unwrap_or(DEFAULT_FPS);
+
+    let mut output = match output_path.extension().and_then(|ext| ext.to_str()) {
+        Some("avi") | Some("mp4") => Output::Video(output_path.clone(), None),
+        _ => {
+            std::fs::create_dir_all(&output_path)?;
+            Output::Images(output_path.clone())
+        }
+    };
+
+    let params = Config::from_env()?.vision;
+    let mut debug = DebugOutput::off();
+    let mut exported = 0;
+
+    for record in &recording.records {
+        let frame = match recording.read_frame(record.frame)? {
+            Some(frame) => frame,
+            None => continue,
+        };
+
+        let frames = process_frame(&frame, &params, &mut debug)?;
+        let annotated = overlay::render(&frame, &frames, record)?;
+        output.write(record.frame, &annotated, fps)?;
+        exported += 1;
+    }
+
+    if exported == 0 {
+        bail!("No recorded frames in {}", session_dir.display());
+    }
+
+    println!(
+        "Exported {} frames at {:.1} fps to {}",
+        exported,
+        fps,
+        output_path.display()
+    );
+    Ok(())
+}
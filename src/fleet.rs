@@ -0,0 +1,326 @@
+//! Runs several cars from one process.
+//!
+//! `cargo run -- fleet [profiles]`
+//!
+//! The profiles file, `cars.json` by default, lists the cars to start:
+//!
+//! ```json
+//! [
+//!     { "name": "Rust Ratio", "color": "#ff9514", "team_id": "rust-1" },
+//!     {
+//!         "name": "Rust Line",
+//!         "color": "#1495ff",
+//!         "team_id": "rust-2",
+//!         "controller": "racing-line",
+//!         "config": "configs/line.json",
+//!         "overrides": { "racing_line": { "max_speed": 1.5 } }
+//!     }
+//! ]
+//! ```
+//!
+//! Every car gets its own connection and thread, and its own directory named after it in the
+//! session directory for telemetry, recordings and debug images. A car's config is read from
+//! its `config` file or the usual config file, then `overrides` are merged into it. The
+//! preview window is not available, and telemetry is only streamed for cars with a
+//! `telemetry_server` address. Log messages are prefixed with the name of the car.
+//!
+//! The track map and racing line files are shared unless the configs say otherwise, which is
+//! fine for reading them, but a car that learns the track needs a `track_map.path` of its own.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    config::Config,
+    driver::{create_session_dir, CarSetup, Driver},
+    stream::TelemetryServer,
+};
+
+const DEFAULT_PROFILES: &str = "cars.json";
+
+/// How a car steers.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Controller {
+    /// The line pixel ratios.
+    Ratio,
+    /// The lane estimate.
+    Estimator,
+    /// The racing line, with the lane estimate until the track is mapped.
+    RacingLine,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CarProfile {
+    pub name: String,
+    pub color: String,
+    pub team_id: String,
+    /// Leaves the config's choice of controller alone if not set.
+    #[serde(default)]
+    pub controller: Option<Controller>,
+    #[serde(default)]
+    pub config: Option<PathBuf>,
+    /// Config fields that differ from `config`, in the same layout. Null leaves a field as it is.
+    #[serde(default)]
+    pub overrides: Value,
+    #[serde(default)]
+    pub telemetry_server: Option<String>,
+}
+
+impl CarProfile {
+    fn load_config(&self) -> anyhow::Result<Config> {
+        let base = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::from_env()?,
+        };
+
+        let mut config = serde_json::to_value(base)?;
+        merge(&mut config, &self.overrides);
+        let mut config: Config = serde_json::from_value(config)
+            .with_context(|| format!("Invalid config overrides for {}", self.name))?;
+
+        match self.controller {
+            Some(Controller::Ratio) => {
+                config.estimator.steer = false;
+                config.racing_line.follow = false;
+            }
+            Some(Controller::Estimator) => {
+                config.estimator.steer = true;
+                config.racing_line.follow = false;
+            }
+            Some(Controller::RacingLine) => {
+                config.estimator.steer = true;
+                config.racing_line.follow = true;
+            }
+            None => {}
+        }
+
+        Ok(config)
+    }
+
+    /// Name usable as a directory name.
+    fn dir_name(&self) -> String {
+        self.name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '-'
+                }
+            })
+            .collect()
+    }
+}
+
+/// Merges the fields of `overrides` into `base`, recursing into objects.
+fn merge(base: &mut Value, overrides: &Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(key) {
+                    Some(field) => merge(field, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (_, Value::Null) => {}
+        (base, overrides) => *base = overrides.clone(),
+    }
+}
+
+fn load_profiles(path: &Path) -> anyhow::Result<Vec<CarProfile>> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let profiles: Vec<CarProfile> = serde_json::from_reader(file)
+        .with_context(|| format!("Invalid car profiles {}", path.display()))?;
+
+    if profiles.is_empty() {
+        bail!("{} lists no cars", path.display());
+    }
+
+    let mut dirs = HashSet::new();
+    for profile in &profiles {
+        if !dirs.insert(profile.dir_name()) {
+            bail!("More than one car is named like {}", profile.name);
+        }
+    }
+
+    Ok(profiles)
+}
+
+/// Makes sure no car learns a track map into a file another car also uses.
+fn check_track_maps(profiles: &[CarProfile], configs: &[Config]) -> anyhow::Result<()> {
+    for (i, (profile, config)) in profiles.iter().zip(configs).enumerate() {
+        if !config.track_map.learn {
+            continue;
+        }
+
+        let shared_with = profiles
+            .iter()
+            .zip(configs)
+            .enumerate()
+            .find(|(j, (_, other))| *j != i && other.track_map.path == config.track_map.path);
+        if let Some((_, (other, _))) = shared_with {
+            bail!(
+                "{} learns the track map {}, which {} uses too, give them their own track_map.path",
+                profile.name,
+                config.track_map.path.display(),
+                other.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let profiles_path = args
+        .first()
+        .map_or(PathBuf::from(DEFAULT_PROFILES), PathBuf::from);
+    let profiles = load_profiles(&profiles_path)?;
+
+    match std::env::var("TELEMETRY").as_deref() {
+        Ok("off") | Err(_) => {}
+        Ok(_) => bail!("TELEMETRY can only be off for a fleet, every car logs to its directory"),
+    }
+    if let Ok("raspi") = std::env::var("BACKEND").as_deref() {
+        bail!("A fleet can only drive in the simulator");
+    }
+
+    // Fail before any car starts moving if a config is broken.
+    let configs = profiles
+        .iter()
+        .map(CarProfile::load_config)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    check_track_maps(&profiles, &configs)?;
+
+    let running = crate::handle_shutdown_signals()?;
+    let session_dir = create_session_dir()?;
+    println!("Session directory is {}", session_dir.display());
+
+    let cars: Vec<_> = profiles
+        .into_iter()
+        .zip(configs)
+        .map(|(profile, config)| {
+            let name = profile.name.clone();
+            let car_dir = session_dir.join(profile.dir_name());
+            let running = running.clone();
+
+            let drive_car = move || -> anyhow::Result<()> {
+                std::fs::create_dir_all(&car_dir)?;
+                let telemetry_server = profile
+                    .telemetry_server
+                    .as_deref()
+                    .map(TelemetryServer::bind)
+                    .transpose()?;
+
+                let mut driver = Driver::new(
+                    config,
+                    CarSetup {
+                        name: profile.name,
+                        color: profile.color,
+                        team_id: profile.team_id,
+                        session_dir: car_dir,
+                        gui: false,
+                        telemetry_server,
+                    },
+                )?;
+                driver.drive(&running)
+            };
+            let car = thread::Builder::new().name(name.clone()).spawn(drive_car);
+
+            (name, car)
+        })
+        .collect();
+
+    let mut failed = 0;
+    for (name, car) in cars {
+        let result = match car {
+            Ok(car) => car
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("The driver panicked"))),
+            Err(error) => Err(error.into()),
+        };
+
+        match result {
+            Ok(()) => println!("{}: finished", name),
+            Err(error) => {
+                eprintln!("{}: {}", name, error);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} of the cars failed", failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn merge_replaces_fields() {
+        let mut base = json!({ "a": 1, "b": "two" });
+        merge(&mut base, &json!({ "b": "three" }));
+        assert_eq!(base, json!({ "a": 1, "b": "three" }));
+    }
+
+    #[test]
+    fn merge_recurses_into_objects() {
+        let mut base = json!({
+            "racing_line": { "max_speed": 2.0, "follow": false },
+            "vision": { "red_threshold": 150.0 }
+        });
+        merge(&mut base, &json!({ "racing_line": { "max_speed": 1.5 } }));
+        assert_eq!(
+            base,
+            json!({
+                "racing_line": { "max_speed": 1.5, "follow": false },
+                "vision": { "red_threshold": 150.0 }
+            })
+        );
+    }
+
+    #[test]
+    fn merge_keeps_fields_overridden_with_null() {
+        let mut base = json!({ "a": 1, "nested": { "b": 2 } });
+        merge(&mut base, &json!({ "a": null, "nested": { "b": null } }));
+        assert_eq!(base, json!({ "a": 1, "nested": { "b": 2 } }));
+    }
+
+    #[test]
+    fn merge_with_null_overrides_changes_nothing() {
+        let mut base = json!({ "a": 1 });
+        merge(&mut base, &Value::Null);
+        assert_eq!(base, json!({ "a": 1 }));
+    }
+
+    #[test]
+    fn merge_adds_missing_fields() {
+        let mut base = json!({ "a": 1 });
+        merge(&mut base, &json!({ "calibration": { "fx": 100.0 } }));
+        assert_eq!(base, json!({ "a": 1, "calibration": { "fx": 100.0 } }));
+    }
+
+    #[test]
+    fn merge_replaces_non_objects_with_objects() {
+        let mut base = json!({ "calibration": null });
+        merge(&mut base, &json!({ "calibration": { "fx": 100.0 } }));
+        assert_eq!(base, json!({ "calibration": { "fx": 100.0 } }));
+    }
+}
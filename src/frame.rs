@@ -0,0 +1,197 @@
+//! Decoding and validation of the frames received from the camera.
+//!
+//! Frames arrive either as encoded images (JPEG or PNG, as sent by the simulator) or as raw
+//! buffers of `FrameConfig::width` by `height` pixels, as a real camera delivers them. Both end
+//! up as the 8-bit BGR `Mat` the pipeline expects.
+//!
+//! A frame that is empty, has the wrong size or isn't 8-bit BGR would otherwise fail deep inside
+//! `process_frame` or produce garbage measurements. What the driver does with a bad frame is
+//! chosen by `FrameConfig::policy`.
+
+use opencv::{
+    core::{Scalar_, CV_8U, CV_8UC1},
+    imgproc::{cvt_color, COLOR_RGB2BGR, COLOR_YUV2BGR_I420, COLOR_YUV2BGR_YUYV},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    vision::decode_frame,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameFormat {
+    /// JPEG, PNG or anything else `imdecode` understands.
+    Encoded,
+    /// Packed 24-bit RGB.
+    Rgb,
+    /// Packed 24-bit BGR.
+    Bgr,
+    /// Packed YUV 4:2:2, two bytes per pixel.
+    Yuyv,
+    /// Planar YUV 4:2:0, a full Y plane followed by quarter size U and V planes.
+    I420,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FramePolicy {
+    /// Process the last good frame again.
+    Reuse,
+    /// Drop the frame and keep the previous commands.
+    Skip,
+    /// End the run.
+    Stop,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrameConfig {
+    pub format: FrameFormat,
+    pub width: i32,
+    pub height: i32,
+    pub policy: FramePolicy,
+    /// Consecutive bad frames the last good one stands in for before `Reuse` falls back to
+    /// `Skip`, so a broken camera still trips the watchdog.
+    pub max_reused: usize,
+}
+
+impl Default for FrameConfig {
+    fn default() -> Self {
+        FrameConfig {
+            format: FrameFormat::Encoded,
+            width: 128,
+            height: 80,
+            policy: FramePolicy::Reuse,
+            max_reused: 5,
+        }
+    }
+}
+
+/// A frame as received, before decoding.
+pub enum ReceivedFrame {
+    /// An encoded image, or a raw frame that arrived with a length header.
+    Bytes(Vec<u8>),
+    /// A raw frame read straight into a buffer from `FrameConfig::raw_buffer`.
+    Raw(Mat),
+}
+
+/// Row count, channels, which are also the bytes per pixel, and conversion to BGR of a raw
+/// frame.
+struct RawLayout {
+    rows: i32,
+    channels: i32,
+    conversion: Option<i32>,
+}
+
+impl FrameConfig {
+    fn raw_layout(&self) -> Option<RawLayout> {
+        let (rows, channels, conversion) = match self.format {
+            FrameFormat::Encoded => return None,
+            FrameFormat::Rgb => (self.height, 3, Some(COLOR_RGB2BGR)),
+            FrameFormat::Bgr => (self.height, 3, None),
+            FrameFormat::Yuyv => (self.height, 2, Some(COLOR_YUV2BGR_YUYV)),
+            FrameFormat::I420 => (self.height * 3 / 2, 1, Some(COLOR_YUV2BGR_I420)),
+        };
+
+        Some(RawLayout {
+            rows,
+            channels,
+            conversion,
+        })
+    }
+
+    /// Size in bytes of a raw frame, None for encoded images.
+    pub fn raw_len(&self) -> Option<usize> {
+        self.raw_layout()
+            .map(|layout| (layout.rows * self.width * layout.channels) as usize)
+    }
+
+    /// Single-channel Mat of `raw_len` bytes that a raw frame can be read into, None for
+    /// encoded images.
+    pub fn raw_buffer(&self) -> Result<Option<Mat>> {
+        match self.raw_layout() {
+            Some(layout) => Ok(Some(Mat::new_rows_cols_with_default(
+                layout.rows,
+                self.width * layout.channels,
+                CV_8UC1,
+                Scalar_::all(0.0),
+            )?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Turns a received frame into a BGR frame according to `config`.
+pub fn decode(image: ReceivedFrame, config: &FrameConfig) -> Result<Mat> {
+    match (config.raw_layout(), image) {
+        (None, ReceivedFrame::Bytes(image)) => decode_frame(image),
+        (Some(layout), ReceivedFrame::Bytes(image)) => decode_raw(&image, config, &layout),
+        (Some(layout), ReceivedFrame::Raw(raw)) => convert_raw(raw, &layout),
+        (None, ReceivedFrame::Raw(_)) => Err(Error::Decode(String::from(
+            "Received a raw frame, but frames.format is encoded",
+        ))),
+    }
+}
+
+fn decode_raw(image: &[u8], config: &FrameConfig, layout: &RawLayout) -> Result<Mat> {
+    let expected_len = (layout.rows * config.width * layout.channels) as usize;
+    if image.len() != expected_len {
+        return Err(Error::Decode(format!(
+            "Raw frame is {} bytes, expected {} for {}x{} {:?}",
+            image.len(),
+            expected_len,
+            config.width,
+            config.height,
+            config.format
+        )));
+    }
+
+    let mut raw = config.raw_buffer()?.unwrap();
+    raw.data_typed_mut::<u8>()?.copy_from_slice(image);
+    convert_raw(raw, layout)
+}
+
+/// Converts a raw frame read into a `FrameConfig::raw_buffer` to BGR. BGR frames are used as
+/// they are, without copying.
+fn convert_raw(raw: Mat, layout: &RawLayout) -> Result<Mat> {
+    let raw = raw.reshape(layout.channels, layout.rows)?;
+
+    match layout.conversion {
+        Some(code) => {
+            let mut frame = Mat::default()?;
+            cvt_color(&raw, &mut frame, code, 0)?;
+            Ok(frame)
+        }
+        None => Ok(raw),
+    }
+}
+
+pub fn validate(frame: &Mat, config: &FrameConfig) -> Result<()> {
+    if frame.rows() == 0 || frame.cols() == 0 {
+        return Err(Error::Decode(String::from("Empty frame")));
+    }
+
+    if frame.cols() != config.width || frame.rows() != config.height {
+        return Err(Error::Decode(format!(
+            "Frame is {}x{}, expected {}x{}",
+            frame.cols(),
+            frame.rows(),
+            config.width,
+            config.height
+        )));
+    }
+
+    let channels = frame.channels()?;
+    if channels != 3 || frame.depth()? != CV_8U {
+        return Err(Error::Decode(format!(
+            "Frame has {} channels of depth {}, expected 8-bit BGR",
+            channels,
+            frame.depth()?
+        )));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,253 @@
+//! Golden-image regression checks for the vision pipeline.
+//!
+//! Every frame in `fixtures/frames` is run through `process_frame` and its blue, green and red
+//! masks and measurements are compared against the ones stored in `fixtures/golden/<frame>/`.
+//!
+//! Every file in `fixtures/corrupt` is a damaged or malformed frame that must be rejected by
+//! `frame::decode` and `frame::validate` with a frame error instead of reaching the pipeline.
+//!
+//! `cargo run -- golden` checks the goldens, `cargo run -- golden --bless` overwrites them after
+//! an intentional change to the pipeline.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+use opencv::{
+    core::{absdiff, count_non_zero},
+    imgcodecs,
+    prelude::*,
+};
+
+use crate::{
+    debug::DebugOutput,
+    error::Error,
+    frame::{self, FrameConfig, ReceivedFrame},
+    recording::image_files,
+    vision::{find_horizon, measure, process_frame, Measurements, VisionParams},
+};
+
+const FRAMES_DIR: &str = "fixtures/frames";
+const CORRUPT_DIR: &str = "fixtures/corrupt";
+const GOLDEN_DIR: &str = "fixtures/golden";
+
+/// Share of pixels that may differ between a mask and its golden.
+const MASK_TOLERANCE: f32 = 0.005;
+const RATIO_TOLERANCE: f32 = 0.005;
+const HORIZON_TOLERANCE: i32 = 1;
+
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let bless = args.iter().any(|arg| arg == "--bless");
+
+    let fixtures = image_files(Path::new(FRAMES_DIR))?;
+    let corrupt_fixtures = image_files(Path::new(CORRUPT_DIR))?;
+    let mut failures = 0;
+
+    for path in &fixtures {
+        let name = path.file_stem().unwrap().to_string_lossy();
+        let golden_dir = golden_dir(path);
+        let output = GoldenOutput::compute(path)?;
+
+        if bless {
+            output.save(&golden_dir)?;
+            println!("blessed {}", name);
+            continue;
+        }
+
+        match output.compare(&golden_dir) {
+            Ok(()) => println!("ok     {}", name),
+            Err(error) => {
+                failures += 1;
+                println!("FAILED {}: {}", name, error);
+            }
+        }
+    }
+
+    let frame_config = FrameConfig::default();
+    let mut accepted = 0;
+
+    for path in &corrupt_fixtures {
+        let name = path.file_name().unwrap().to_string_lossy();
+
+        match check_rejected(path, &frame_config) {
+            Ok(error) => println!("ok     {} ({})", name, error),
+            Err(error) => {
+                accepted += 1;
+                println!("FAILED {}: {}", name, error);
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!(
+            "{} of {} fixtures differ from their goldens, run `cargo run -- golden --bless` if the change is intended",
+            failures,
+            fixtures.len()
+        );
+    }
+    if accepted > 0 {
+        bail!(
+            "{} of {} corrupt fixtures were not rejected",
+            accepted,
+            corrupt_fixtures.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks that the corrupt frame at `path` is rejected with a frame error, and returns it.
+fn check_rejected(path: &Path, frame_config: &FrameConfig) -> anyhow::Result<Error> {
+    let image = ReceivedFrame::Bytes(std::fs::read(path)?);
+
+    match frame::decode(image, frame_config).and_then(|frame| frame::validate(&frame, frame_config))
+    {
+        Err(error) if error.is_frame_error() => Ok(error),
+        Err(error) => bail!("not a frame error: {}", error),
+        Ok(()) => bail!("accepted as a valid frame"),
+    }
+}
+
+/// Directory of the goldens of the fixture frame at `path`.
+fn golden_dir(path: &Path) -> PathBuf {
+    Path::new(GOLDEN_DIR).join(path.file_stem().unwrap())
+}
+
+struct GoldenOutput {
+    masks: Vec<(&'static str, Mat)>,
+    measurements: Measurements,
+}
+
+impl GoldenOutput {
+    fn compute(path: &Path) -> anyhow::Result<GoldenOutput> {
+        let frame = imgcodecs::imread(&path.to_string_lossy(), imgcodecs::IMREAD_COLOR)?;
+        if frame.empty()? {
+            bail!("could not read fixture {}", path.display());
+        }
+
+        let mut debug = DebugOutput::off();
+        let frames = process_frame(&frame, &VisionParams::default(), &mut debug)?;
+        let horizon = find_horizon(&frames)?;
+        let measurements = measure(&frames, horizon, &mut debug)?;
+
+        let masks = vec![
+            ("blue", frames[0].1.clone()),
+            ("green", frames[1].2.clone()),
+            ("red", frames[2].3.clone()),
+        ];
+
+        Ok(GoldenOutput {
+            masks,
+            measurements,
+        })
+    }
+
+    fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)?;
+
+        for (name, mask) in &self.masks {
+            let path = dir.join(format!("{}.png", name));
+            imgcodecs::imwrite(
+                &path.to_string_lossy(),
+                mask,
+                &opencv::core::Vector::<i32>::new(),
+            )?;
+        }
+
+        let file = File::create(dir.join("measurements.json"))?;
+        serde_json::to_writer_pretty(file, &self.measurements)?;
+        Ok(())
+    }
+
+    fn compare(&self, dir: &Path) -> anyhow::Result<()> {
+        if !dir.exists() {
+            bail!("no golden in {}", dir.display());
+        }
+
+        for (name, mask) in &self.masks {
+            let path = dir.join(format!("{}.png", name));
+            let golden = imgcodecs::imread(&path.to_string_lossy(), imgcodecs::IMREAD_GRAYSCALE)?;
+
+            if golden.rows() != mask.rows() || golden.cols() != mask.cols() {
+                bail!(
+                    "{} mask is {}x{}, golden is {}x{}",
+                    name,
+                    mask.cols(),
+                    mask.rows(),
+                    golden.cols(),
+                    golden.rows()
+                );
+            }
+
+            let mut diff = Mat::default()?;
+            absdiff(mask, &golden, &mut diff)?;
+            let differing = count_non_zero(&diff)? as f32 / (mask.rows() * mask.cols()) as f32;
+
+            if differing > MASK_TOLERANCE {
+                bail!(
+                    "{} mask differs in {:.2}% of pixels",
+                    name,
+                    differing * 100.0
+                );
+            }
+        }
+
+        let golden: Measurements =
+            serde_json::from_reader(File::open(dir.join("measurements.json"))?)?;
+        let actual = &self.measurements;
+
+        if (actual.horizon - golden.horizon).abs() > HORIZON_TOLERANCE {
+            bail!(
+                "horizon is at row {}, golden is {}",
+                actual.horizon,
+                golden.horizon
+            );
+        }
+
+        let ratios = [
+            ("blue", actual.blue_ratio, golden.blue_ratio),
+            ("green", actual.green_ratio, golden.green_ratio),
+            ("red", actual.red_ratio, golden.red_ratio),
+        ];
+
+        for (name, actual, golden) in ratios.iter() {
+            if (actual - golden).abs() > RATIO_TOLERANCE {
+                bail!("{} ratio is {:.4}, golden is {:.4}", name, actual, golden);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixtures_match_goldens() {
+        let fixtures = image_files(Path::new(FRAMES_DIR)).unwrap();
+        assert!(!fixtures.is_empty());
+
+        for path in &fixtures {
+            let output = GoldenOutput::compute(path).unwrap();
+            if let Err(error) = output.compare(&golden_dir(path)) {
+                panic!("{}: {}", path.display(), error);
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_fixtures_are_rejected() {
+        let fixtures = image_files(Path::new(CORRUPT_DIR)).unwrap();
+        assert!(!fixtures.is_empty());
+
+        for path in &fixtures {
+            if let Err(error) = check_rejected(path, &FrameConfig::default()) {
+                panic!("{}: {}", path.display(), error);
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Interactive debugger for stepping through a recorded session.
+//!
+//! `cargo run -- inspect <session dir>`
+//!
+//! For one recorded frame at a time this shows the annotated frame, the channels and masks of
+//! `process_frame` and the horizon candidate scores, and prints the recorded controller outputs
+//! next to the measurements recomputed with the current thresholds. The thresholds can be tuned
+//! live with the trackbars of the masks window.
+//!
+//! Keys: `d` or `.` steps forward, `a` or `,` steps back, space plays and pauses, `p` prints the
+//! current thresholds as JSON and `q` or Esc quits.
+
+use anyhow::{anyhow, bail};
+use opencv::{
+    core::{hconcat, vconcat, Point_, Scalar_, Size, CV_8UC3},
+    highgui,
+    imgproc::{line, resize, INTER_NEAREST, LINE_8},
+    prelude::*,
+    types::VectorOfMat,
+};
+
+use crate::{
+    config::Config,
+    debug::DebugOutput,
+    overlay,
+    recording::{self, Recording},
+    telemetry::TelemetryRecord,
+    vision::{find_horizon, horizon_scores, measure, process_frame, ChannelFrames, VisionParams},
+};
+
+const OVERLAY_WINDOW: &str = "inspect";
+const MASKS_WINDOW: &str = "inspect: masks";
+const HORIZON_WINDOW: &str = "inspect: horizon";
+
+const MASKS_SCALE: i32 = 2;
+const HORIZON_SCALE: i32 = 4;
+const HORIZON_CHART_WIDTH: i32 = 256;
+
+const TRACKBARS: [(&str, i32); 5] = [
+    ("black", 255),
+    ("blue", 255),
+    ("green", 255),
+    ("red", 255),
+    ("erode", 10),
+];
+
+fn trackbar_values(params: &VisionParams) -> [i32; 5] {
+    [
+        params.black_threshold as i32,
+        params.blue_threshold as i32,
+        params.green_threshold as i32,
+        params.red_threshold as i32,
+        params.erode_size,
+    ]
+}
+
+fn params_from_trackbars(values: [i32; 5]) -> VisionParams {
+    VisionParams {
+        black_threshold: values[0] as f64,
+        blue_threshold: values[1] as f64,
+        green_threshold: values[2] as f64,
+        red_threshold: values[3] as f64,
+        erode_size: values[4].max(1),
+    }
+}
+
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let session_dir = match args.first() {
+        Some(dir) => std::path::Path::new(dir.as_str()),
+        None => bail!("Usage: inspect <session dir>"),
+    };
+
+    let recording = Recording::open(session_dir)?;
+    let records: Vec<&TelemetryRecord> = recording
+        .records
+        .iter()
+        .filter(|record| recording::frame_path(&recording.dir, record.frame).exists())
+        .collect();
+
+    if records.is_empty() {
+        bail!("No recorded frames in {}", session_dir.display());
+    }
+
+    let params = Config::from_env()?.vision;
+    let play_delay = recording
+        .fps()
+        .map_or(50, |fps| (1000.0 / fps) as i32)
+        .max(1);
+
+    highgui::named_window(OVERLAY_WINDOW, highgui::WINDOW_AUTOSIZE)?;
+    highgui::named_window(MASKS_WINDOW, highgui::WINDOW_AUTOSIZE)?;
+    highgui::named_window(HORIZON_WINDOW, highgui::WINDOW_AUTOSIZE)?;
+
+    // OpenCV keeps pointers to these, so they have to live as long as the windows.
+    let mut trackbar_storage = trackbar_values(&params);
+    for (i, (name, max)) in TRACKBARS.iter().enumerate() {
+        highgui::create_trackbar(name, MASKS_WINDOW, &mut trackbar_storage[i], *max, None)?;
+        highgui::set_trackbar_pos(name, MASKS_WINDOW, trackbar_storage[i])?;
+    }
+
+    let mut index = 0;
+    let mut playing = false;
+    let mut shown = None;
+
+    loop {
+        let mut values = [0; 5];
+        for (i, (name, _)) in TRACKBARS.iter().enumerate() {
+            values[i] = highgui::get_trackbar_pos(name, MASKS_WINDOW)?;
+        }
+        let params = params_from_trackbars(values);
+
+        if shown != Some((index, values)) {
+            show(&recording, records[index], &params)?;
+            shown = Some((index, values));
+        }
+
+        let key = highgui::wait_key(if playing { play_delay } else { 30 })?;
+
+        match key {
+            27 => break,
+            _ if key == 'q' as i32 => break,
+            _ if key == 'd' as i32 || key == '.' as i32 => {
+                index = (index + 1).min(records.len() - 1);
+            }
+            _ if key == 'a' as i32 || key == ',' as i32 => {
+                index = index.saturating_sub(1);
+            }
+            _ if key == ' ' as i32 => playing = !playing,
+            _ if key == 'p' as i32 => println!("{}", serde_json::to_string_pretty(&params)?),
+            _ if playing => {
+                if index + 1 < records.len() {
+                    index += 1;
+                } else {
+                    playing = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    highgui::destroy_all_windows()?;
+    Ok(())
+}
+
+fn show(
+    recording: &Recording,
+    record: &TelemetryRecord,
+    params: &VisionParams,
+) -> anyhow::Result<()> {
+    let frame = recording
+        .read_frame(record.frame)?
+        .ok_or_else(|| anyhow!("Frame {} is missing", record.frame))?;
+
+    let mut debug = DebugOutput::off();
+    let frames = process_frame(&frame, params, &mut debug)?;
+    let scores = horizon_scores(&frames)?;
+    let horizon = find_horizon(&frames)?;
+    let measurements = measure(&frames, record.horizon_interpolated, &mut debug)?;
+
+    highgui::imshow(OVERLAY_WINDOW, &overlay::render(&frame, &frames, record)?)?;
+    highgui::imshow(MASKS_WINDOW, &mask_mosaic(&frames)?)?;
+    highgui::imshow(
+        HORIZON_WINDOW,
+        &horizon_chart(&scores, horizon, record.horizon_interpolated)?,
+    )?;
+
+    println!(
+        "frame {}: recorded horizon {} (interpolated {}), b {:.3} g {:.3} r {:.3} -> speed {:.3} forward {:.3} turn {:+.3}",
+        record.frame,
+        record.horizon,
+        record.horizon_interpolated,
+        record.blue_ratio,
+        record.green_ratio,
+        record.red_ratio,
+        record.speed,
+        record.forward,
+        record.turn
+    );
+    println!(
+        "  recomputed horizon {}, b {:.3} g {:.3} r {:.3}",
+        horizon, measurements.blue_ratio, measurements.green_ratio, measurements.red_ratio
+    );
+
+    Ok(())
+}
+
+/// Eroded blue, green and red channels on top, their threshold masks below.
+fn mask_mosaic(frames: &[ChannelFrames]) -> anyhow::Result<Mat> {
+    let mut channels = VectorOfMat::new();
+    channels.push(frames[0].0.clone());
+    channels.push(frames[1].0.clone());
+    channels.push(frames[2].0.clone());
+
+    let mut masks = VectorOfMat::new();
+    masks.push(frames[0].1.clone());
+    masks.push(frames[1].2.clone());
+    masks.push(frames[2].3.clone());
+
+    let mut rows = VectorOfMat::new();
+    for parts in [channels, masks].iter() {
+        let mut row = Mat::default()?;
+        hconcat(parts, &mut row)?;
+        rows.push(row);
+    }
+
+    let mut mosaic = Mat::default()?;
+    vconcat(&rows, &mut mosaic)?;
+
+    let mut scaled = Mat::default()?;
+    resize(
+        &mosaic,
+        &mut scaled,
+        Size {
+            width: mosaic.cols() * MASKS_SCALE,
+            height: mosaic.rows() * MASKS_SCALE,
+        },
+        0.0,
+        0.0,
+        INTER_NEAREST,
+    )?;
+
+    Ok(scaled)
+}
+
+/// Bar per candidate row, with the picked horizon in red and the interpolated one in yellow.
+fn horizon_chart(
+    scores: &[(i32, f32)],
+    horizon: i32,
+    horizon_interpolated: i32,
+) -> anyhow::Result<Mat> {
+    let rows = scores.len() as i32 * HORIZON_SCALE;
+    let mut chart = Mat::new_rows_cols_with_default(
+        rows.max(HORIZON_SCALE),
+        HORIZON_CHART_WIDTH,
+        CV_8UC3,
+        Scalar_([0.0, 0.0, 0.0, 0.0]),
+    )?;
+
+    for (y, score) in scores {
+        let color = if *y == horizon {
+            Scalar_([0.0, 0.0, 255.0, 0.0])
+        } else if *y == horizon_interpolated {
+            Scalar_([0.0, 255.0, 255.0, 0.0])
+        } else {
+            Scalar_([160.0, 160.0, 160.0, 0.0])
+        };
+
+        let chart_y = y * HORIZON_SCALE + HORIZON_SCALE / 2;
+        line(
+            &mut chart,
+            Point_ { x: 0, y: chart_y },
+            Point_ {
+                x: (score * HORIZON_CHART_WIDTH as f32) as i32,
+                y: chart_y,
+            },
+            color,
+            HORIZON_SCALE - 1,
+            LINE_8,
+            0,
+        )?;
+    }
+
+    Ok(chart)
+}
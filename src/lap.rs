@@ -0,0 +1,155 @@
+//! Lap counting from the start/finish line seen by the camera.
+//!
+//! The line is found as a share of at least `min_coverage` pixels within `color_tolerance` of
+//! `marker_color` in the bottom `band` of the frame. A crossing only counts once the marker has
+//! been out of sight for `clear_frames` frames and `min_lap_time` has passed since the previous
+//! crossing, so standing on the line or a marker-colored flicker doesn't end a lap. The first
+//! crossing starts the timing, every following one completes a lap.
+
+use std::time::{Duration, Instant};
+
+use opencv::{
+    core::{count_non_zero, Rect_},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Result,
+    vision::{color_mask, parse_color},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LapConfig {
+    /// Color of the start/finish line as `#rrggbb`, None disables lap counting.
+    pub marker_color: Option<String>,
+    /// Largest difference per channel from the marker color.
+    pub color_tolerance: f64,
+    /// Share of the frame height at the bottom that is searched for the marker.
+    pub band: f32,
+    /// Share of the band that has to be marker-colored.
+    pub min_coverage: f32,
+    /// Frames without the marker before the next crossing counts.
+    pub clear_frames: usize,
+    /// Shortest possible lap, in seconds.
+    pub min_lap_time: f32,
+}
+
+impl Default for LapConfig {
+    fn default() -> Self {
+        LapConfig {
+            marker_color: None,
+            color_tolerance: 40.0,
+            band: 0.15,
+            min_coverage: 0.3,
+            clear_frames: 5,
+            min_lap_time: 5.0,
+        }
+    }
+}
+
+/// A completed lap, the count is kept by `LapCounter::laps`.
+#[derive(Debug, Clone, Copy)]
+pub struct LapEvent {
+    pub time: Duration,
+}
+
+pub struct LapCounter {
+    config: LapConfig,
+    /// Marker color as blue, green and red, None when disabled.
+    color: Option<[f64; 3]>,
+    clear_frames: usize,
+    lap_start: Option<Instant>,
+    laps: usize,
+    best: Option<Duration>,
+}
+
+impl LapCounter {
+    pub fn new(config: LapConfig) -> anyhow::Result<LapCounter> {
+        let color = config
+            .marker_color
+            .as_deref()
+            .map(parse_color)
+            .transpose()?;
+
+        Ok(LapCounter {
+            clear_frames: config.clear_frames,
+            config,
+            color,
+            lap_start: None,
+            laps: 0,
+            best: None,
+        })
+    }
+
+    /// Completed laps so far.
+    pub fn laps(&self) -> usize {
+        self.laps
+    }
+
+    /// Looks for the marker in `frame`, seen at `now`. Returns the lap completed by crossing
+    /// it, if any.
+    pub fn update(&mut self, frame: &Mat, now: Instant) -> Result<Option<LapEvent>> {
+        let color = match self.color {
+            Some(color) => color,
+            None => return Ok(None),
+        };
+
+        if self.marker_coverage(frame, color)? < self.config.min_coverage {
+            self.clear_frames += 1;
+            return Ok(None);
+        }
+
+        let was_clear = self.clear_frames >= self.config.clear_frames;
+        self.clear_frames = 0;
+        if !was_clear {
+            return Ok(None);
+        }
+
+        let lap_start = match self.lap_start {
+            Some(lap_start) => lap_start,
+            None => {
+                log!("Crossed the start line, timing laps");
+                self.lap_start = Some(now);
+                return Ok(None);
+            }
+        };
+
+        let time = now - lap_start;
+        if time.as_secs_f32() < self.config.min_lap_time {
+            return Ok(None);
+        }
+
+        self.lap_start = Some(now);
+        self.laps += 1;
+        let best = self.best.map_or(time, |best| best.min(time));
+        self.best = Some(best);
+        log!(
+            "Lap {}: {:.2} s (best {:.2} s)",
+            self.laps,
+            time.as_secs_f32(),
+            best.as_secs_f32()
+        );
+
+        Ok(Some(LapEvent { time }))
+    }
+
+    /// Share of the bottom band of `frame` that has the marker color.
+    fn marker_coverage(&self, frame: &Mat, color: [f64; 3]) -> Result<f32> {
+        let height =
+            ((frame.rows() as f32 * self.config.band).round() as i32).clamp(1, frame.rows());
+        let band = Mat::roi(
+            frame,
+            Rect_ {
+                x: 0,
+                y: frame.rows() - height,
+                width: frame.cols(),
+                height,
+            },
+        )?;
+
+        let mask = color_mask(&band, color, self.config.color_tolerance)?;
+        Ok(count_non_zero(&mask)? as f32 / (height * frame.cols()).max(1) as f32)
+    }
+}
@@ -0,0 +1,250 @@
+//! Detection of other cars and obstacles on the track.
+//!
+//! Two kinds of blobs below the horizon count as obstacles: pixels within `color_tolerance` of
+//! one of `car_colors`, given as `#rrggbb` like the color sent at login, and with
+//! `detect_unknown` set, pixels that are neither black nor part of the blue, green or red masks
+//! of `process_frame`. Blobs smaller than `min_area` are ignored.
+//!
+//! The controller slows down for the nearest obstacle and steers away from it when it is in
+//! front of the car. How close it is comes from the camera calibration if there is one,
+//! otherwise from how far down the frame its bottom edge is.
+
+use opencv::{
+    core::{bitwise_and, bitwise_not, bitwise_or, in_range, no_array, Rect_, Scalar_, CV_32S},
+    imgproc::{
+        connected_components_with_stats, CC_STAT_AREA, CC_STAT_HEIGHT, CC_STAT_LEFT, CC_STAT_TOP,
+        CC_STAT_WIDTH,
+    },
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use crate::{
+    calibration::Camera,
+    debug::DebugOutput,
+    error::{Error, Result},
+    vision::{color_mask, parse_color, roi_below, ChannelFrames},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ObstacleConfig {
+    /// Colors of the other cars, as `#rrggbb`.
+    pub car_colors: Vec<String>,
+    /// Largest difference per channel from a car color.
+    pub color_tolerance: f64,
+    /// Also detect blobs that aren't track colors.
+    pub detect_unknown: bool,
+    /// Smallest blob that is an obstacle, in pixels.
+    pub min_area: i32,
+    /// Slow down and steer around detected obstacles.
+    pub avoid: bool,
+    /// Distance at which an obstacle starts to matter, in meters, with a camera calibration.
+    pub caution_distance: f32,
+    /// Closeness at which an obstacle starts to matter without a camera calibration.
+    pub caution_closeness: f32,
+    /// Share of the forward value taken away by an obstacle right in front of the car.
+    pub slowdown: f32,
+    /// Turn away from an obstacle right in front of the car.
+    pub avoid_turn: f32,
+    /// Obstacles further to the side than this are passed without steering, -1 to 1.
+    pub path_width: f32,
+}
+
+impl Default for ObstacleConfig {
+    fn default() -> Self {
+        ObstacleConfig {
+            car_colors: Vec::new(),
+            color_tolerance: 40.0,
+            detect_unknown: false,
+            min_area: 12,
+            avoid: true,
+            caution_distance: 1.5,
+            caution_closeness: 0.3,
+            slowdown: 0.7,
+            avoid_turn: 0.5,
+            path_width: 0.6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Obstacle {
+    /// Bounding box in the frame.
+    pub rect: Rect_<i32>,
+    /// Horizontal center, -1 at the left edge of the frame and 1 at the right.
+    pub position: f32,
+    /// Where the bottom edge is between the horizon, 0, and the bottom of the frame, 1.
+    pub closeness: f32,
+    /// Meters ahead of the camera, with a camera calibration.
+    pub distance: Option<f32>,
+}
+
+impl Obstacle {
+    /// How urgently the obstacle needs avoiding, from 0 when it's far away to 1 when it's
+    /// about to be hit.
+    pub fn threat(&self, config: &ObstacleConfig) -> f32 {
+        let threat = match self.distance {
+            Some(distance) => 1.0 - distance / config.caution_distance,
+            None => (self.closeness - config.caution_closeness) / (1.0 - config.caution_closeness),
+        };
+        threat.clamp(0.0, 1.0)
+    }
+
+    /// Adjusts the forward and turn values to avoid the obstacle.
+    pub fn avoid(&self, forward: f32, turn: f32, config: &ObstacleConfig) -> (f32, f32) {
+        let threat = self.threat(config);
+        let forward = forward * (1.0 - config.slowdown * threat);
+
+        if self.position.abs() > config.path_width {
+            return (forward, turn);
+        }
+
+        // Steer to the side with more room, the obstacle's position is positive on the right.
+        let away = -self.position.signum();
+        (forward, turn + away * config.avoid_turn * threat)
+    }
+}
+
+pub struct ObstacleDetector {
+    config: ObstacleConfig,
+    /// `car_colors` as blue, green and red.
+    colors: Vec<[f64; 3]>,
+}
+
+impl ObstacleDetector {
+    pub fn new(config: ObstacleConfig) -> anyhow::Result<ObstacleDetector> {
+        let colors = config
+            .car_colors
+            .iter()
+            .map(|color| parse_color(color))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(ObstacleDetector { config, colors })
+    }
+
+    pub fn config(&self) -> &ObstacleConfig {
+        &self.config
+    }
+
+    /// Finds the obstacles below `horizon`, nearest first.
+    pub fn detect(
+        &self,
+        frame: &Mat,
+        frames: &[ChannelFrames],
+        horizon: i32,
+        camera: Option<&Camera>,
+        debug: &mut DebugOutput,
+    ) -> Result<Vec<Obstacle>> {
+        let mask = match self.mask(frame, frames)? {
+            Some(mask) => mask,
+            None => return Ok(Vec::new()),
+        };
+
+        let roi = roi_below(frames, horizon);
+        if roi.height <= 0 || roi.width <= 0 {
+            return Err(Error::Vision(String::from("Horizon is outside the frame")));
+        }
+        let roi_mask = Mat::roi(&mask, roi)?;
+        debug.save("obstacles", &roi_mask);
+
+        let mut labels = Mat::default()?;
+        let mut stats = Mat::default()?;
+        let mut centroids = Mat::default()?;
+        let count = connected_components_with_stats(
+            &roi_mask,
+            &mut labels,
+            &mut stats,
+            &mut centroids,
+            8,
+            CV_32S,
+        )?;
+
+        let half_width = frame.cols() as f32 / 2.0;
+        let mut obstacles = Vec::new();
+        // Label 0 is the background.
+        for label in 1..count {
+            if *stats.at_2d::<i32>(label, CC_STAT_AREA)? < self.config.min_area {
+                continue;
+            }
+
+            let rect = Rect_ {
+                x: *stats.at_2d::<i32>(label, CC_STAT_LEFT)?,
+                y: *stats.at_2d::<i32>(label, CC_STAT_TOP)? + roi.y,
+                width: *stats.at_2d::<i32>(label, CC_STAT_WIDTH)?,
+                height: *stats.at_2d::<i32>(label, CC_STAT_HEIGHT)?,
+            };
+            let center_x = rect.x as f32 + rect.width as f32 / 2.0;
+            let bottom = rect.y + rect.height - 1;
+
+            let distance = match camera {
+                Some(camera) => camera
+                    .pixel_to_ground(center_x, bottom as f32)?
+                    .map(|(_, ahead)| ahead),
+                None => None,
+            };
+
+            obstacles.push(Obstacle {
+                rect,
+                position: (center_x - half_width) / half_width,
+                closeness: (bottom - roi.y) as f32 / roi.height as f32,
+                distance,
+            });
+        }
+
+        obstacles.sort_by(|a, b| {
+            PartialOrd::partial_cmp(&b.closeness, &a.closeness).unwrap_or(Ordering::Equal)
+        });
+        Ok(obstacles)
+    }
+
+    /// Mask of the pixels that may belong to an obstacle, None if nothing is detected.
+    fn mask(&self, frame: &Mat, frames: &[ChannelFrames]) -> Result<Option<Mat>> {
+        let mut mask: Option<Mat> = None;
+
+        for &color in &self.colors {
+            let matching = color_mask(frame, color, self.config.color_tolerance)?;
+            mask = Some(combine(mask, matching)?);
+        }
+
+        if self.config.detect_unknown {
+            // The eroded channels are zero where the frame is black.
+            let mut blue_or_green = Mat::default()?;
+            bitwise_or(&frames[0].0, &frames[1].0, &mut blue_or_green, &no_array()?)?;
+            let mut any_channel = Mat::default()?;
+            bitwise_or(&blue_or_green, &frames[2].0, &mut any_channel, &no_array()?)?;
+            let mut not_black = Mat::default()?;
+            in_range(
+                &any_channel,
+                &Scalar_::all(1.0),
+                &Scalar_::all(255.0),
+                &mut not_black,
+            )?;
+
+            let mut lines = Mat::default()?;
+            bitwise_or(&frames[0].1, &frames[1].2, &mut lines, &no_array()?)?;
+            let mut track = Mat::default()?;
+            bitwise_or(&lines, &frames[2].3, &mut track, &no_array()?)?;
+            let mut not_track = Mat::default()?;
+            bitwise_not(&track, &mut not_track, &no_array()?)?;
+
+            let mut unknown = Mat::default()?;
+            bitwise_and(&not_black, &not_track, &mut unknown, &no_array()?)?;
+            mask = Some(combine(mask, unknown)?);
+        }
+
+        Ok(mask)
+    }
+}
+
+fn combine(mask: Option<Mat>, other: Mat) -> Result<Mat> {
+    match mask {
+        Some(mask) => {
+            let mut combined = Mat::default()?;
+            bitwise_or(&mask, &other, &mut combined, &no_array()?)?;
+            Ok(combined)
+        }
+        None => Ok(other),
+    }
+}
@@ -0,0 +1,165 @@
+//! Dead reckoning of the car's position on the track.
+//!
+//! Every frame the distance travelled and the change of heading are estimated twice: from the
+//! commands sent for the previous frame and from how far the track in the lower half of the
+//! image moved since the previous frame, found with phase correlation. The two are blended with
+//! `OdometryConfig::visual_weight`, frames where the correlation is too weak use the commands
+//! alone.
+//!
+//! With `lap_length` set the distance is also turned into lap progress, and `corners` lists
+//! known corners by their progress so the controller can slow down before reaching them.
+
+use opencv::{
+    core::{no_array, Rect_, CV_32F},
+    imgproc::{cvt_color, phase_correlate, COLOR_BGR2GRAY},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+/// A corner at a known place on the lap, in lap progress from 0 to 1.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownCorner {
+    pub start: f32,
+    pub end: f32,
+    /// Forward value not to exceed in and just before the corner.
+    pub max_forward: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OdometryConfig {
+    /// Distance travelled in one frame at forward 1.0, in meters.
+    pub meters_per_forward: f32,
+    /// Heading change per meter at turn 1.0, in radians.
+    pub radians_per_turn: f32,
+    /// Distance travelled per pixel the track moves down the image.
+    pub meters_per_pixel: f32,
+    /// Heading change per pixel the track moves sideways in the image.
+    pub radians_per_pixel: f32,
+    /// Share of the visual estimate in the blend, 0 uses the commands only.
+    pub visual_weight: f32,
+    /// Phase correlation response under which the visual estimate is ignored.
+    pub min_response: f64,
+    /// Length of one lap in meters, enables lap progress and known corners.
+    pub lap_length: Option<f32>,
+    /// How far ahead known corners are anticipated, in meters.
+    pub lookahead: f32,
+    pub corners: Vec<KnownCorner>,
+}
+
+impl Default for OdometryConfig {
+    fn default() -> Self {
+        OdometryConfig {
+            meters_per_forward: 0.5,
+            radians_per_turn: 2.0,
+            meters_per_pixel: 0.01,
+            radians_per_pixel: 0.005,
+            visual_weight: 0.5,
+            min_response: 0.1,
+            lap_length: None,
+            lookahead: 1.0,
+            corners: Vec::new(),
+        }
+    }
+}
+
+/// Position relative to where the car started, x to the right and y ahead of the starting
+/// direction, in meters. Heading is in radians, positive to the right.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Pose {
+    pub x: f32,
+    pub y: f32,
+    pub heading: f32,
+    pub distance: f32,
+}
+
+pub struct Odometry {
+    config: OdometryConfig,
+    pose: Pose,
+    previous: Option<Mat>,
+}
+
+impl Odometry {
+    pub fn new(config: OdometryConfig) -> Odometry {
+        Odometry {
+            config,
+            pose: Pose::default(),
+            previous: None,
+        }
+    }
+
+    /// Advances the pose to `frame`, which was seen after driving the previous frame with
+    /// `forward` and `turn`.
+    pub fn update(&mut self, frame: &Mat, forward: f32, turn: f32) -> Result<Pose> {
+        let config = &self.config;
+        let mut distance = forward * config.meters_per_forward;
+        let mut heading_change = turn * config.radians_per_turn * distance;
+
+        let current = lower_half(frame)?;
+        if let Some(previous) = &self.previous {
+            if previous.rows() == current.rows() && previous.cols() == current.cols() {
+                let mut response = 0.0;
+                let shift = phase_correlate(previous, &current, &no_array()?, &mut response)?;
+
+                if response >= config.min_response {
+                    // The track slides down the image when driving forward and sideways
+                    // against the direction of a turn.
+                    let weight = config.visual_weight;
+                    let visual_distance = shift.y as f32 * config.meters_per_pixel;
+                    let visual_heading_change = -shift.x as f32 * config.radians_per_pixel;
+                    distance = (1.0 - weight) * distance + weight * visual_distance;
+                    heading_change =
+                        (1.0 - weight) * heading_change + weight * visual_heading_change;
+                }
+            }
+        }
+        self.previous = Some(current);
+
+        let pose = &mut self.pose;
+        pose.heading += heading_change;
+        pose.x += distance * pose.heading.sin();
+        pose.y += distance * pose.heading.cos();
+        pose.distance += distance;
+        Ok(*pose)
+    }
+
+    /// Share of the current lap driven, None without a lap length.
+    pub fn lap_progress(&self) -> Option<f32> {
+        let lap_length = self.config.lap_length?;
+        Some(self.pose.distance.rem_euclid(lap_length) / lap_length)
+    }
+
+    /// The known corner the car is in or reaches within `lookahead`.
+    pub fn upcoming_corner(&self) -> Option<&KnownCorner> {
+        let progress = self.lap_progress()?;
+        let lookahead = self.config.lookahead / self.config.lap_length?;
+
+        self.config.corners.iter().find(|corner| {
+            let until_start = (corner.start - progress).rem_euclid(1.0);
+            let since_start = (progress - corner.start).rem_euclid(1.0);
+            let corner_length = (corner.end - corner.start).rem_euclid(1.0);
+            until_start <= lookahead || since_start <= corner_length
+        })
+    }
+}
+
+/// Grayscale lower half of the frame as floats, the part of the track phase correlation tracks.
+fn lower_half(frame: &Mat) -> Result<Mat> {
+    let half = Mat::roi(
+        frame,
+        Rect_ {
+            x: 0,
+            y: frame.rows() / 2,
+            width: frame.cols(),
+            height: frame.rows() - frame.rows() / 2,
+        },
+    )?;
+
+    let mut gray = Mat::default()?;
+    cvt_color(&half, &mut gray, COLOR_BGR2GRAY, 0)?;
+    let mut float = Mat::default()?;
+    gray.convert_to(&mut float, CV_32F, 1.0, 0.0)?;
+    Ok(float)
+}
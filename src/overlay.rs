@@ -0,0 +1,247 @@
+//! Annotated visualization of a processed frame, shared by the preview window, the telemetry
+//! stream and exported recordings.
+
+use opencv::{
+    core::{add_weighted, Point_, Rect_, Scalar_, Size},
+    imgproc::{
+        circle, line, put_text, rectangle, resize, FONT_HERSHEY_SIMPLEX, INTER_NEAREST, LINE_8,
+    },
+    prelude::*,
+};
+
+use crate::{
+    obstacle::Obstacle,
+    telemetry::TelemetryRecord,
+    vision::{find_track_edges, roi_below, ChannelFrames},
+};
+
+/// Frames are tiny, so the overlay is drawn on an upscaled copy to keep the text readable.
+const SCALE: i32 = 4;
+const MASK_OPACITY: f64 = 0.5;
+/// Forward value that fills the speed gauge.
+const MAX_FORWARD: f32 = 0.2;
+
+const BLUE: Scalar_<f64> = Scalar_([255.0, 64.0, 0.0, 0.0]);
+const GREEN: Scalar_<f64> = Scalar_([0.0, 255.0, 0.0, 0.0]);
+const RED: Scalar_<f64> = Scalar_([0.0, 0.0, 255.0, 0.0]);
+const YELLOW: Scalar_<f64> = Scalar_([0.0, 255.0, 255.0, 0.0]);
+const MAGENTA: Scalar_<f64> = Scalar_([255.0, 0.0, 255.0, 0.0]);
+const WHITE: Scalar_<f64> = Scalar_([255.0, 255.0, 255.0, 0.0]);
+const GRAY: Scalar_<f64> = Scalar_([96.0, 96.0, 96.0, 0.0]);
+
+pub fn render(
+    frame: &Mat,
+    frames: &[ChannelFrames],
+    record: &TelemetryRecord,
+) -> anyhow::Result<Mat> {
+    let mut colored = frame.clone();
+    colored.set_to(&BLUE, &frames[0].1)?;
+    colored.set_to(&GREEN, &frames[1].2)?;
+    colored.set_to(&RED, &frames[2].3)?;
+
+    let mut blended = Mat::default()?;
+    add_weighted(
+        frame,
+        1.0 - MASK_OPACITY,
+        &colored,
+        MASK_OPACITY,
+        0.0,
+        &mut blended,
+        -1,
+    )?;
+
+    let width = frame.cols() * SCALE;
+    let height = frame.rows() * SCALE;
+
+    let mut viz = Mat::default()?;
+    resize(
+        &blended,
+        &mut viz,
+        Size { width, height },
+        0.0,
+        0.0,
+        INTER_NEAREST,
+    )?;
+
+    let roi = roi_below(frames, record.horizon_interpolated);
+    rectangle(&mut viz, scale_rect(roi), YELLOW, 1, LINE_8, 0)?;
+
+    line(
+        &mut viz,
+        Point_ {
+            x: 0,
+            y: record.horizon * SCALE,
+        },
+        Point_ {
+            x: width,
+            y: record.horizon * SCALE,
+        },
+        MAGENTA,
+        1,
+        LINE_8,
+        0,
+    )?;
+
+    draw_track_edges(&mut viz, frames, roi)?;
+    draw_gauges(&mut viz, record)?;
+
+    let lines = [
+        format!("forward {:.3}  turn {:+.2}", record.forward, record.turn),
+        format!(
+            "speed {:.3}  wheels {:+.2}",
+            record.speed, record.wheels_turn
+        ),
+        format!(
+            "b {:.2}  g {:.2}  r {:.2}",
+            record.blue_ratio, record.green_ratio, record.red_ratio
+        ),
+    ];
+
+    for (i, text) in lines.iter().enumerate() {
+        put_text(
+            &mut viz,
+            text,
+            Point_ {
+                x: 6,
+                y: 16 + i as i32 * 16,
+            },
+            FONT_HERSHEY_SIMPLEX,
+            0.45,
+            WHITE,
+            1,
+            LINE_8,
+            false,
+        )?;
+    }
+
+    Ok(viz)
+}
+
+/// Outlines `obstacles` on a frame rendered by `render`, the nearest one in white.
+pub fn draw_obstacles(viz: &mut Mat, obstacles: &[Obstacle]) -> anyhow::Result<()> {
+    for (i, obstacle) in obstacles.iter().enumerate() {
+        let color = if i == 0 { WHITE } else { GRAY };
+        rectangle(viz, scale_rect(obstacle.rect), color, 2, LINE_8, 0)?;
+    }
+
+    Ok(())
+}
+
+fn scale_rect(rect: Rect_<i32>) -> Rect_<i32> {
+    Rect_ {
+        x: rect.x * SCALE,
+        y: rect.y * SCALE,
+        width: rect.width * SCALE,
+        height: rect.height * SCALE,
+    }
+}
+
+fn scale_point(x: i32, y: i32) -> Point_<i32> {
+    Point_ {
+        x: x * SCALE + SCALE / 2,
+        y: y * SCALE + SCALE / 2,
+    }
+}
+
+fn draw_track_edges(
+    viz: &mut Mat,
+    frames: &[ChannelFrames],
+    roi: Rect_<i32>,
+) -> anyhow::Result<()> {
+    let rows = find_track_edges(frames, roi, 4);
+
+    for row in &rows {
+        if let Some(x) = row.green {
+            circle(viz, scale_point(x, row.y), 3, GREEN, -1, LINE_8, 0)?;
+        }
+        if let Some(x) = row.red {
+            circle(viz, scale_point(x, row.y), 3, RED, -1, LINE_8, 0)?;
+        }
+    }
+
+    let centers: Vec<_> = rows
+        .iter()
+        .filter_map(|row| row.center().map(|x| scale_point(x, row.y)))
+        .collect();
+
+    for pair in centers.windows(2) {
+        line(viz, pair[0], pair[1], YELLOW, 2, LINE_8, 0)?;
+    }
+
+    Ok(())
+}
+
+fn draw_gauges(viz: &mut Mat, record: &TelemetryRecord) -> anyhow::Result<()> {
+    let width = viz.cols();
+    let height = viz.rows();
+
+    // Steering: a horizontal bar from the bottom center towards the turn direction.
+    let steering_y = height - 10;
+    let center_x = width / 2;
+    line(
+        viz,
+        Point_ {
+            x: 10,
+            y: steering_y,
+        },
+        Point_ {
+            x: width - 10,
+            y: steering_y,
+        },
+        GRAY,
+        6,
+        LINE_8,
+        0,
+    )?;
+    line(
+        viz,
+        Point_ {
+            x: center_x,
+            y: steering_y,
+        },
+        Point_ {
+            x: center_x + (record.turn * (center_x - 10) as f32) as i32,
+            y: steering_y,
+        },
+        YELLOW,
+        6,
+        LINE_8,
+        0,
+    )?;
+
+    // Speed: a vertical bar on the right edge filling upwards, red when reversing.
+    let speed_x = width - 10;
+    let bottom = height - 24;
+    let top = 24;
+    let fill = (record.forward.abs() / MAX_FORWARD).clamp(0.0, 1.0);
+    let speed_color = if record.forward < 0.0 { RED } else { GREEN };
+    line(
+        viz,
+        Point_ {
+            x: speed_x,
+            y: bottom,
+        },
+        Point_ { x: speed_x, y: top },
+        GRAY,
+        6,
+        LINE_8,
+        0,
+    )?;
+    line(
+        viz,
+        Point_ {
+            x: speed_x,
+            y: bottom,
+        },
+        Point_ {
+            x: speed_x,
+            y: bottom - ((bottom - top) as f32 * fill) as i32,
+        },
+        speed_color,
+        6,
+        LINE_8,
+        0,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! Normalizes camera frames before `process_frame`.
+//!
+//! Frames are turned upright, cropped and scaled in that order, so the pipeline sees the same
+//! kind of image from the simulator and from the real car. Recorded frames are stored after
+//! preprocessing, which keeps replays and exports independent of the camera that produced them.
+
+use opencv::{
+    core::{flip, rotate, Rect_, Size, ROTATE_180},
+    imgproc::{resize, INTER_AREA},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use anyhow::bail;
+
+use crate::{
+    error::{Error, Result},
+    frame::FrameConfig,
+};
+
+/// How the camera is mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Orientation {
+    Upright,
+    FlipVertical,
+    FlipHorizontal,
+    Rotate180,
+}
+
+/// Pixels cut from each edge of the upright frame, e.g. the car's hood at the bottom.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Crop {
+    pub top: i32,
+    pub bottom: i32,
+    pub left: i32,
+    pub right: i32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct FrameSize {
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreprocessConfig {
+    pub orientation: Orientation,
+    pub crop: Crop,
+    /// Size the cropped frame is scaled to, None keeps it as is.
+    pub resize: Option<FrameSize>,
+}
+
+impl Default for PreprocessConfig {
+    fn default() -> Self {
+        PreprocessConfig {
+            orientation: Orientation::Upright,
+            crop: Crop::default(),
+            resize: None,
+        }
+    }
+}
+
+impl PreprocessConfig {
+    /// Size of the preprocessed frames, checking that the crop and the resize fit the frames
+    /// described by `frames`.
+    pub fn output_size(&self, frames: &FrameConfig) -> anyhow::Result<FrameSize> {
+        let crop = &self.crop;
+        let width = frames.width - crop.left - crop.right;
+        let height = frames.height - crop.top - crop.bottom;
+        if crop.top < 0 || crop.bottom < 0 || crop.left < 0 || crop.right < 0 {
+            bail!("preprocess.crop can't be negative");
+        }
+        if width <= 0 || height <= 0 {
+            bail!(
+                "preprocess.crop doesn't leave anything of {}x{} frames",
+                frames.width,
+                frames.height
+            );
+        }
+
+        match self.resize {
+            Some(size) if size.width <= 0 || size.height <= 0 => {
+                bail!("preprocess.resize has to be larger than 0x0")
+            }
+            Some(size) => Ok(size),
+            None => Ok(FrameSize { width, height }),
+        }
+    }
+}
+
+pub fn preprocess(frame: Mat, config: &PreprocessConfig) -> Result<Mat> {
+    let frame = orient(frame, config.orientation)?;
+    let frame = crop(frame, &config.crop)?;
+
+    match config.resize {
+        Some(size) if size.width != frame.cols() || size.height != frame.rows() => {
+            let mut resized = Mat::default()?;
+            resize(
+                &frame,
+                &mut resized,
+                Size {
+                    width: size.width,
+                    height: size.height,
+                },
+                0.0,
+                0.0,
+                INTER_AREA,
+            )?;
+            Ok(resized)
+        }
+        _ => Ok(frame),
+    }
+}
+
+fn orient(frame: Mat, orientation: Orientation) -> Result<Mat> {
+    let mut oriented = Mat::default()?;
+    match orientation {
+        Orientation::Upright => return Ok(frame),
+        Orientation::FlipVertical => flip(&frame, &mut oriented, 0)?,
+        Orientation::FlipHorizontal => flip(&frame, &mut oriented, 1)?,
+        Orientation::Rotate180 => rotate(&frame, &mut oriented, ROTATE_180)?,
+    }
+
+    Ok(oriented)
+}
+
+fn crop(frame: Mat, crop: &Crop) -> Result<Mat> {
+    if crop.top == 0 && crop.bottom == 0 && crop.left == 0 && crop.right == 0 {
+        return Ok(frame);
+    }
+
+    let width = frame.cols() - crop.left - crop.right;
+    let height = frame.rows() - crop.top - crop.bottom;
+    if crop.top < 0
+        || crop.bottom < 0
+        || crop.left < 0
+        || crop.right < 0
+        || width <= 0
+        || height <= 0
+    {
+        return Err(Error::Vision(String::from(
+            "Crop doesn't leave anything of the frame",
+        )));
+    }
+
+    let roi = Mat::roi(
+        &frame,
+        Rect_ {
+            x: crop.left,
+            y: crop.top,
+            width,
+            height,
+        },
+    )?;
+
+    // Copy so the pipeline gets a continuous Mat instead of a view into the full frame.
+    Ok(roi.try_clone()?)
+}
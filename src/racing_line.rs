@@ -0,0 +1,389 @@
+//! Racing line optimized over a learned track map.
+//!
+//! `cargo run -- racing-line [track map] [output] [--iterations N]`
+//!
+//! The optimizer pulls every point of the centerline towards the middle of its neighbours,
+//! within the track's width minus `margin`, until the line straightens out as far as the track
+//! allows. The speed along the line is then limited by `max_lateral_accel` in the corners and
+//! by `max_accel` and `max_brake` between them, and converted to forward values with
+//! `forward_per_mps`. The track map and output default to `TrackMapConfig::path` and
+//! `RacingLineConfig::path`.
+//!
+//! With `follow` set the driver steers towards the line's lateral offset at its position on the
+//! track map, measured by the lane estimate, and drives at the line's forward values.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    estimator::Lane,
+    track_map::{load_json, save_json, TrackProfile},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RacingLineConfig {
+    pub path: PathBuf,
+    /// Drive along the racing line when it and the track map are available.
+    pub follow: bool,
+    /// Distance kept from the lines, in meters.
+    pub margin: f32,
+    pub iterations: usize,
+    pub max_speed: f32,
+    pub max_lateral_accel: f32,
+    pub max_accel: f32,
+    pub max_brake: f32,
+    /// Forward value per meter per second.
+    pub forward_per_mps: f32,
+    /// Turn per meter of lateral error.
+    pub offset_gain: f32,
+    /// Turn per unit of the lane's heading.
+    pub heading_gain: f32,
+    /// Turn per unit of the line's curvature, steers into corners before any error builds up.
+    pub curvature_gain: f32,
+}
+
+impl Default for RacingLineConfig {
+    fn default() -> Self {
+        RacingLineConfig {
+            path: PathBuf::from("tracks/default-line.json"),
+            follow: false,
+            margin: 0.1,
+            iterations: 500,
+            max_speed: 2.0,
+            max_lateral_accel: 2.0,
+            max_accel: 1.0,
+            max_brake: 2.0,
+            forward_per_mps: 0.1,
+            offset_gain: 1.5,
+            heading_gain: 0.4,
+            curvature_gain: 0.2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RacingPoint {
+    /// Distance from the start of the lap along the centerline, in meters.
+    pub start: f32,
+    /// Distance of the line to the right of the centerline, in meters.
+    pub offset: f32,
+    /// Heading change per meter along the line, in radians.
+    pub curvature: f32,
+    /// Speed in meters per second.
+    pub speed: f32,
+    pub forward: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RacingLine {
+    pub lap_length: f32,
+    pub width: f32,
+    pub points: Vec<RacingPoint>,
+}
+
+impl RacingLine {
+    pub fn load(path: &Path) -> anyhow::Result<RacingLine> {
+        let line: RacingLine = load_json(path, "racing line")?;
+        line.validate()
+            .with_context(|| format!("Invalid racing line {}", path.display()))?;
+        Ok(line)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !(self.lap_length.is_finite() && self.lap_length > 0.0) {
+            bail!("lap_length has to be positive, got {}", self.lap_length);
+        }
+        if self.points.is_empty() {
+            bail!("no points");
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        save_json(self, path)
+    }
+
+    /// Checks that the line was optimized for a track of the same length as `profile`.
+    pub fn check_track(&self, profile: &TrackProfile) -> anyhow::Result<()> {
+        if (self.lap_length - profile.lap_length).abs() > 1e-3 * profile.lap_length {
+            bail!(
+                "The racing line is for a {:.2} m lap, but the track map is {:.2} m, run racing-line again",
+                self.lap_length,
+                profile.lap_length
+            );
+        }
+        Ok(())
+    }
+
+    /// Loads the line to follow if `config.follow` is set.
+    pub fn from_config(config: &RacingLineConfig) -> anyhow::Result<Option<RacingLine>> {
+        if !config.follow {
+            return Ok(None);
+        }
+
+        let line = RacingLine::load(&config.path)?;
+        log!(
+            "Following a racing line for a {:.1} m lap from {}",
+            line.lap_length,
+            config.path.display()
+        );
+        Ok(Some(line))
+    }
+
+    fn point_at(&self, position: f32) -> &RacingPoint {
+        let segment_length = self.lap_length / self.points.len() as f32;
+        let i = (position.rem_euclid(self.lap_length) / segment_length) as usize;
+        &self.points[i.min(self.points.len() - 1)]
+    }
+
+    /// Forward and turn values that bring the car onto the line `position` meters into the lap.
+    pub fn follow(&self, position: f32, lane: &Lane, config: &RacingLineConfig) -> (f32, f32) {
+        let point = self.point_at(position);
+
+        // The lane offset is the centerline's distance to the right of the car in half frame
+        // widths, which is taken to span half the track.
+        let car_offset = -lane.offset * self.width / 2.0;
+        let turn = config.offset_gain * (point.offset - car_offset)
+            + config.heading_gain * lane.heading
+            + config.curvature_gain * point.curvature;
+
+        (point.forward, turn)
+    }
+}
+
+/// Computes the racing line and its speed profile for `profile`.
+pub fn optimize(profile: &TrackProfile, config: &RacingLineConfig) -> RacingLine {
+    let count = profile.segments.len();
+    let segment_length = profile.lap_length / count as f32;
+    let half_width = (profile.width / 2.0 - config.margin).max(0.0);
+
+    let centers: Vec<(f32, f32)> = profile.segments.iter().map(|s| (s.x, s.y)).collect();
+    let normals: Vec<(f32, f32)> = profile
+        .segments
+        .iter()
+        .map(|s| (s.heading.cos(), -s.heading.sin()))
+        .collect();
+    let point = |offsets: &[f32], i: usize| {
+        let (x, y) = centers[i];
+        let (nx, ny) = normals[i];
+        (x + offsets[i] * nx, y + offsets[i] * ny)
+    };
+
+    let mut offsets = vec![0.0; count];
+    for _ in 0..config.iterations {
+        for i in 0..count {
+            let (px, py) = point(&offsets, (i + count - 1) % count);
+            let (qx, qy) = point(&offsets, (i + 1) % count);
+            let (cx, cy) = centers[i];
+            let (nx, ny) = normals[i];
+            let mid = ((px + qx) / 2.0 - cx, (py + qy) / 2.0 - cy);
+            offsets[i] = (mid.0 * nx + mid.1 * ny).clamp(-half_width, half_width);
+        }
+    }
+
+    let curvatures: Vec<f32> = (0..count)
+        .map(|i| {
+            curvature(
+                point(&offsets, (i + count - 1) % count),
+                point(&offsets, i),
+                point(&offsets, (i + 1) % count),
+            )
+        })
+        .collect();
+
+    let mut speeds: Vec<f32> = curvatures
+        .iter()
+        .map(|k| {
+            if k.abs() < 1e-6 {
+                config.max_speed
+            } else {
+                (config.max_lateral_accel / k.abs())
+                    .sqrt()
+                    .min(config.max_speed)
+            }
+        })
+        .collect();
+
+    // Twice around the lap so the limits carry over the start line.
+    for _ in 0..2 {
+        for i in 0..count {
+            let previous = speeds[(i + count - 1) % count];
+            let reachable = (previous * previous + 2.0 * config.max_accel * segment_length).sqrt();
+            speeds[i] = speeds[i].min(reachable);
+        }
+        for i in (0..count).rev() {
+            let next = speeds[(i + 1) % count];
+            let stoppable = (next * next + 2.0 * config.max_brake * segment_length).sqrt();
+            speeds[i] = speeds[i].min(stoppable);
+        }
+    }
+
+    let points = (0..count)
+        .map(|i| RacingPoint {
+            start: profile.segments[i].start,
+            offset: offsets[i],
+            curvature: curvatures[i],
+            speed: speeds[i],
+            forward: speeds[i] * config.forward_per_mps,
+        })
+        .collect();
+
+    RacingLine {
+        lap_length: profile.lap_length,
+        width: profile.width,
+        points,
+    }
+}
+
+/// Signed curvature of the circle through three points, positive when turning right.
+fn curvature(a: (f32, f32), b: (f32, f32), c: (f32, f32)) -> f32 {
+    let ab = (b.0 - a.0, b.1 - a.1);
+    let bc = (c.0 - b.0, c.1 - b.1);
+    let ac = (c.0 - a.0, c.1 - a.1);
+    let lengths = ab.0.hypot(ab.1) * bc.0.hypot(bc.1) * ac.0.hypot(ac.1);
+    if lengths < 1e-9 {
+        return 0.0;
+    }
+
+    // With x to the right and y ahead, a clockwise turn has a negative cross product.
+    -2.0 * (ab.0 * bc.1 - ab.1 * bc.0) / lengths
+}
+
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    let mut line_config = config.racing_line.clone();
+    let mut positional = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--iterations" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--iterations needs a value"))?;
+                line_config.iterations = value.parse()?;
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let map_path = positional
+        .first()
+        .map_or(config.track_map.path.clone(), PathBuf::from);
+    let output_path = positional
+        .get(1)
+        .map_or(line_config.path.clone(), PathBuf::from);
+
+    let profile = TrackProfile::load(&map_path)?;
+    if profile.segments.len() < 3 {
+        bail!(
+            "{} has too few segments for a racing line",
+            map_path.display()
+        );
+    }
+
+    let line = optimize(&profile, &line_config);
+    let lap_time: f32 = line
+        .points
+        .iter()
+        .map(|point| profile.lap_length / line.points.len() as f32 / point.speed)
+        .sum();
+
+    line.save(&output_path)?;
+
+    println!(
+        "Racing line for a {:.1} m lap, estimated {:.1} s, saved to {}",
+        profile.lap_length,
+        lap_time,
+        output_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+    use crate::track_map::Segment;
+
+    const RADIUS: f32 = 2.0;
+
+    /// Point `angle` radians around a circle that starts at the origin heading ahead and turns
+    /// right, mirrored to turn left if `right` is unset.
+    fn on_circle(angle: f32, right: bool) -> (f32, f32) {
+        let x = RADIUS - RADIUS * angle.cos();
+        (if right { x } else { -x }, RADIUS * angle.sin())
+    }
+
+    /// A circular track of `count` segments, driven clockwise if `right` is set.
+    fn circle(count: usize, right: bool) -> TrackProfile {
+        let lap_length = 2.0 * PI * RADIUS;
+        let sign = if right { 1.0 } else { -1.0 };
+        let segments = (0..count)
+            .map(|i| {
+                let angle = 2.0 * PI * i as f32 / count as f32;
+                let (x, y) = on_circle(angle, right);
+                Segment {
+                    start: lap_length * i as f32 / count as f32,
+                    x,
+                    y,
+                    heading: sign * angle,
+                    curvature: sign / RADIUS,
+                    max_forward: 1.0,
+                }
+            })
+            .collect();
+
+        TrackProfile {
+            lap_length,
+            width: 0.6,
+            segments,
+        }
+    }
+
+    #[test]
+    fn curvature_is_positive_turning_right() {
+        let points = |right| {
+            (
+                on_circle(-0.1, right),
+                on_circle(0.0, right),
+                on_circle(0.1, right),
+            )
+        };
+
+        let (a, b, c) = points(true);
+        assert!((curvature(a, b, c) - 1.0 / RADIUS).abs() < 1e-3);
+        let (a, b, c) = points(false);
+        assert!((curvature(a, b, c) + 1.0 / RADIUS).abs() < 1e-3);
+    }
+
+    #[test]
+    fn curvature_of_a_straight_or_degenerate_line_is_zero() {
+        assert_eq!(curvature((0.0, 0.0), (0.0, 1.0), (0.0, 2.0)), 0.0);
+        assert_eq!(curvature((0.0, 1.0), (0.0, 1.0), (0.0, 1.0)), 0.0);
+    }
+
+    #[test]
+    fn optimized_line_keeps_to_the_inside_of_a_corner() {
+        let config = RacingLineConfig::default();
+        let half_width = 0.6 / 2.0 - config.margin;
+
+        // Offsets are to the right of the centerline, which is the inside of a right turn.
+        let line = optimize(&circle(36, true), &config);
+        for point in &line.points {
+            assert!((point.offset - half_width).abs() < 1e-3, "{:?}", point);
+            assert!(point.curvature > 0.0, "{:?}", point);
+        }
+
+        let line = optimize(&circle(36, false), &config);
+        for point in &line.points {
+            assert!((point.offset + half_width).abs() < 1e-3, "{:?}", point);
+            assert!(point.curvature < 0.0, "{:?}", point);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Backend for the physical car on a Raspberry Pi.
+//!
+//! The camera server streams raw frames back to back over a unix socket, without any header,
+//! so every frame is exactly `FrameConfig::raw_len` bytes. Commands go to the motor server as
+//! JSON lines in the same format the simulator uses.
+
+use std::{
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use opencv::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connection::Command,
+    error::{Error, Result},
+    frame::{FrameConfig, ReceivedFrame},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RaspiConfig {
+    pub camera_socket: PathBuf,
+    pub motor_socket: PathBuf,
+}
+
+impl Default for RaspiConfig {
+    fn default() -> Self {
+        RaspiConfig {
+            camera_socket: PathBuf::from("/tmp/camera.sock"),
+            motor_socket: PathBuf::from("/tmp/motor-server.socket"),
+        }
+    }
+}
+
+/// Enables or disables the motors.
+#[derive(Serialize)]
+struct MoveMessage {
+    #[serde(rename = "move")]
+    enabled: bool,
+}
+
+pub struct RaspiConnection {
+    camera: UnixStream,
+    motor: UnixStream,
+    frame_config: FrameConfig,
+    /// First byte of the next frame, read while waiting for it.
+    first_byte: Option<u8>,
+}
+
+impl RaspiConnection {
+    pub fn connect(config: &RaspiConfig, frame_config: &FrameConfig) -> Result<RaspiConnection> {
+        if frame_config.raw_len().is_none() {
+            return Err(Error::Protocol(String::from(
+                "The camera sends raw frames, set frames.format to rgb, bgr, yuyv or i420",
+            )));
+        }
+
+        let camera = UnixStream::connect(&config.camera_socket)?;
+        let motor = UnixStream::connect(&config.motor_socket)?;
+        log!("Connected to motor server");
+
+        let mut connection = RaspiConnection {
+            camera,
+            motor,
+            frame_config: frame_config.clone(),
+            first_byte: None,
+        };
+        connection.write_line(&MoveMessage { enabled: true })?;
+
+        Ok(connection)
+    }
+
+    pub fn send(&mut self, command: &Command) -> Result<()> {
+        self.write_line(command)
+    }
+
+    /// Waits until the next frame starts arriving. Returns false if it didn't within `timeout`.
+    pub fn wait_for_image(&mut self, timeout: Duration) -> Result<bool> {
+        if self.first_byte.is_some() {
+            return Ok(true);
+        }
+
+        let mut byte = [0u8; 1];
+        self.camera.set_read_timeout(Some(timeout))?;
+        let result = self.camera.read(&mut byte);
+        self.camera.set_read_timeout(None)?;
+
+        match result {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) => {
+                self.first_byte = Some(byte[0]);
+                Ok(true)
+            }
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Reads the next frame straight into a Mat, without copying it afterwards.
+    pub fn read_next_image(&mut self) -> Result<ReceivedFrame> {
+        let mut frame = self.frame_config.raw_buffer()?.unwrap();
+        let buffer = frame.data_typed_mut::<u8>()?;
+        let start = match self.first_byte.take() {
+            Some(byte) => {
+                buffer[0] = byte;
+                1
+            }
+            None => 0,
+        };
+
+        self.camera.read_exact(&mut buffer[start..])?;
+        Ok(ReceivedFrame::Raw(frame))
+    }
+
+    pub fn close(&mut self) {
+        self.write_line(&MoveMessage { enabled: false }).ok();
+        self.camera.shutdown(Shutdown::Both).ok();
+        self.motor.shutdown(Shutdown::Both).ok();
+    }
+
+    fn write_line<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        serde_json::to_writer(&mut self.motor, message)?;
+        self.motor.write_all(b"\n")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+//! Reads back sessions recorded with `RECORD=1`: the original frames in `frames/` and the
+//! telemetry log next to them.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use opencv::{imgcodecs, prelude::*};
+
+use crate::telemetry::{self, TelemetryRecord};
+
+pub fn frame_path(session_dir: &Path, frame: usize) -> PathBuf {
+    session_dir
+        .join("frames")
+        .join(format!("frame{:05}.png", frame))
+}
+
+/// The PNG and JPEG images in `dir`, sorted by name.
+pub fn image_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut images = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("png") | Some("jpg") => images.push(path),
+            _ => {}
+        }
+    }
+
+    images.sort();
+    Ok(images)
+}
+
+pub struct Recording {
+    pub dir: PathBuf,
+    pub records: Vec<TelemetryRecord>,
+}
+
+impl Recording {
+    pub fn open(dir: &Path) -> anyhow::Result<Recording> {
+        let telemetry_path = ["telemetry.csv", "telemetry.jsonl"]
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.exists());
+
+        let records = match telemetry_path {
+            Some(path) => telemetry::read(&path)?,
+            None => bail!("No telemetry log in {}", dir.display()),
+        };
+
+        Ok(Recording {
+            dir: dir.to_owned(),
+            records,
+        })
+    }
+
+    /// Reads the original frame, or `None` if it wasn't recorded.
+    pub fn read_frame(&self, frame: usize) -> anyhow::Result<Option<Mat>> {
+        let path = frame_path(&self.dir, frame);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let mat = imgcodecs::imread(&path.to_string_lossy(), imgcodecs::IMREAD_COLOR)?;
+        if mat.empty()? {
+            bail!("Could not decode {}", path.display());
+        }
+
+        Ok(Some(mat))
+    }
+
+    /// Average frame rate of the recording, based on the telemetry timestamps.
+    pub fn fps(&self) -> Option<f64> {
+        let first = self.records.first()?;
+        let last = self.records.last()?;
+        let duration_ms = last.time_ms.checked_sub(first.time_ms)?;
+
+        if duration_ms == 0 {
+            return None;
+        }
+
+        Some((self.records.len() - 1) as f64 * 1000.0 / duration_ms as f64)
+    }
+}
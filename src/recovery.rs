@@ -0,0 +1,244 @@
+//! Detection of the car leaving the track or getting stuck, and the maneuver that brings it
+//! back.
+//!
+//! The car is off the track when less than `min_track_ratio` of the region below the horizon
+//! is blue, green or red for `off_track_frames` frames in a row, and stuck when the frame barely
+//! changes for `stuck_frames` frames while driving forward. Either starts a scripted recovery:
+//! stop, reverse, then crawl with the wheels turned towards the side the track was last seen on
+//! until the lines are back in sight. A recovery that doesn't find the track within
+//! `search_ms` is retried up to `max_attempts` times before it's left to the watchdog.
+
+use std::time::{Duration, Instant};
+
+use opencv::{
+    core::{absdiff, mean, no_array},
+    imgproc::{cvt_color, COLOR_BGR2GRAY},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, estimator::Lane, vision::Measurements};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecoveryConfig {
+    pub enabled: bool,
+    /// Share of track-colored pixels under which the car is off the track.
+    pub min_track_ratio: f32,
+    pub off_track_frames: usize,
+    /// Mean gray level change between frames under which the car isn't moving.
+    pub min_motion: f64,
+    /// Smallest forward value, either way, at which the car is expected to move.
+    pub min_moving_forward: f32,
+    pub stuck_frames: usize,
+    pub stop_ms: u64,
+    pub reverse_ms: u64,
+    /// Forward value while reversing, negative.
+    pub reverse_forward: f32,
+    /// Longest search for the track after reversing.
+    pub search_ms: u64,
+    pub search_forward: f32,
+    pub search_turn: f32,
+    /// Frames the track has to stay in sight before normal control resumes.
+    pub found_frames: usize,
+    pub max_attempts: usize,
+}
+
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        RecoveryConfig {
+            enabled: true,
+            min_track_ratio: 0.01,
+            off_track_frames: 5,
+            min_motion: 1.0,
+            min_moving_forward: 0.05,
+            stuck_frames: 20,
+            stop_ms: 300,
+            reverse_ms: 800,
+            reverse_forward: -0.08,
+            search_ms: 3000,
+            search_forward: 0.04,
+            search_turn: 0.8,
+            found_frames: 3,
+            max_attempts: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RecoveryPhase {
+    Stop,
+    Reverse,
+    Search,
+}
+
+pub struct Recovery {
+    config: RecoveryConfig,
+    /// Current phase and when it started, None while driving normally.
+    phase: Option<(RecoveryPhase, Instant)>,
+    attempts: usize,
+    off_track_frames: usize,
+    stuck_frames: usize,
+    found_frames: usize,
+    /// Turn direction towards where the track was last seen, positive to the right.
+    track_side: f32,
+    previous: Option<Mat>,
+}
+
+impl Recovery {
+    pub fn new(config: RecoveryConfig) -> Recovery {
+        Recovery {
+            config,
+            phase: None,
+            attempts: 0,
+            off_track_frames: 0,
+            stuck_frames: 0,
+            found_frames: 0,
+            track_side: 1.0,
+            previous: None,
+        }
+    }
+
+    pub fn phase(&self) -> Option<RecoveryPhase> {
+        self.phase.map(|(phase, _)| phase)
+    }
+
+    /// Checks `frame`, seen at `now` after driving the previous frame with `forward`. Returns
+    /// the forward and turn values of the recovery maneuver, None to leave the car to the
+    /// controller.
+    pub fn update(
+        &mut self,
+        frame: &Mat,
+        measurements: &Measurements,
+        lane: Option<Lane>,
+        forward: f32,
+        now: Instant,
+    ) -> Result<Option<(f32, f32)>> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+
+        let config = &self.config;
+        let track_ratio =
+            measurements.blue_ratio + measurements.green_ratio + measurements.red_ratio;
+        let on_track = track_ratio.is_finite() && track_ratio >= config.min_track_ratio;
+        if on_track {
+            self.off_track_frames = 0;
+            if let Some(lane) = lane {
+                self.track_side = if lane.offset < 0.0 { -1.0 } else { 1.0 };
+            }
+        } else {
+            self.off_track_frames += 1;
+        }
+
+        let moving = self.moving(frame)?;
+        if moving || forward.abs() < self.config.min_moving_forward {
+            self.stuck_frames = 0;
+        } else {
+            self.stuck_frames += 1;
+        }
+
+        match self.phase {
+            None => {
+                let off_track = self.off_track_frames >= self.config.off_track_frames;
+                let stuck = self.stuck_frames >= self.config.stuck_frames;
+                if !off_track && !stuck {
+                    self.attempts = 0;
+                    return Ok(None);
+                }
+                if self.attempts >= self.config.max_attempts {
+                    // Given up, the watchdog stops the car if the track stays out of sight.
+                    return Ok(None);
+                }
+
+                log_error!(
+                    "Recovery: {}",
+                    if off_track { "off the track" } else { "stuck" }
+                );
+                self.start(now);
+            }
+            Some((RecoveryPhase::Search, _)) if on_track => {
+                self.found_frames += 1;
+                if self.found_frames >= self.config.found_frames {
+                    log!("Recovery: back on the track");
+                    self.phase = None;
+                    self.off_track_frames = 0;
+                    self.stuck_frames = 0;
+                    return Ok(None);
+                }
+            }
+            Some(_) => self.found_frames = 0,
+        }
+
+        Ok(self.advance(now))
+    }
+
+    /// Whether `frame` differs enough from the previous one for the car to be moving.
+    fn moving(&mut self, frame: &Mat) -> Result<bool> {
+        let mut gray = Mat::default()?;
+        cvt_color(frame, &mut gray, COLOR_BGR2GRAY, 0)?;
+
+        let moving = match &self.previous {
+            Some(previous) if previous.rows() == gray.rows() && previous.cols() == gray.cols() => {
+                let mut difference = Mat::default()?;
+                absdiff(previous, &gray, &mut difference)?;
+                mean(&difference, &no_array()?)?[0] >= self.config.min_motion
+            }
+            _ => true,
+        };
+
+        self.previous = Some(gray);
+        Ok(moving)
+    }
+
+    fn start(&mut self, now: Instant) {
+        self.attempts += 1;
+        self.found_frames = 0;
+        self.phase = Some((RecoveryPhase::Stop, now));
+    }
+
+    /// Moves through the phases as their time runs out and returns the phase's commands.
+    fn advance(&mut self, now: Instant) -> Option<(f32, f32)> {
+        let config = &self.config;
+        let (phase, started) = self.phase?;
+        let elapsed = now - started;
+
+        let next = match phase {
+            RecoveryPhase::Stop if elapsed >= Duration::from_millis(config.stop_ms) => {
+                Some(RecoveryPhase::Reverse)
+            }
+            RecoveryPhase::Reverse if elapsed >= Duration::from_millis(config.reverse_ms) => {
+                Some(RecoveryPhase::Search)
+            }
+            RecoveryPhase::Search if elapsed >= Duration::from_millis(config.search_ms) => {
+                if self.attempts >= config.max_attempts {
+                    log_error!("Recovery: gave up after {} attempts", self.attempts);
+                    self.phase = None;
+                    return None;
+                }
+                Some(RecoveryPhase::Stop)
+            }
+            _ => None,
+        };
+
+        if let Some(next) = next {
+            if next == RecoveryPhase::Stop {
+                self.attempts += 1;
+            }
+            self.phase = Some((next, now));
+        }
+
+        match self.phase() {
+            Some(RecoveryPhase::Stop) => Some((0.0, 0.0)),
+            // Backing up with the wheels turned away from the track points the car at it.
+            Some(RecoveryPhase::Reverse) => Some((
+                config.reverse_forward,
+                -self.track_side * config.search_turn,
+            )),
+            Some(RecoveryPhase::Search) => {
+                Some((config.search_forward, self.track_side * config.search_turn))
+            }
+            None => None,
+        }
+    }
+}
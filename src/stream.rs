@@ -0,0 +1,220 @@
+//! Streams telemetry and annotated frames to clients over TCP, so a run can be watched from
+//! another machine.
+//!
+//! Enabled by setting `TELEMETRY_SERVER` to a listen address such as `0.0.0.0:11001`. Each frame
+//! is sent to every connected client as a big-endian `u32` length followed by the JSON encoded
+//! `TelemetryRecord`, and another `u32` length followed by the annotated frame as JPEG.
+//!
+//! Encoding and sending happen on a background thread. If it's still busy with the previous
+//! frame the new one is dropped, so slow clients never hold up the control loop.
+//!
+//! `cargo run -- watch <address>` connects to a server and shows the stream, printing the
+//! measurements of every frame.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::bail;
+use opencv::{highgui, imgcodecs, prelude::*, types::VectorOfu8};
+
+use crate::telemetry::TelemetryRecord;
+
+const JPEG_QUALITY: i32 = 80;
+
+const WATCH_WINDOW: &str = "watch";
+
+/// Connected clients and their count, which can be read without waiting for a send to finish.
+#[derive(Default)]
+struct Clients {
+    streams: Mutex<Vec<TcpStream>>,
+    count: AtomicUsize,
+}
+
+pub struct TelemetryServer {
+    frames: SyncSender<(TelemetryRecord, Mat)>,
+    clients: Arc<Clients>,
+}
+
+impl TelemetryServer {
+    pub fn from_env() -> anyhow::Result<Option<TelemetryServer>> {
+        match std::env::var("TELEMETRY_SERVER") {
+            Ok(address) => Ok(Some(TelemetryServer::bind(&address)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn bind(address: &str) -> anyhow::Result<TelemetryServer> {
+        let listener = TcpListener::bind(address)?;
+        log!("Streaming telemetry on {}", listener.local_addr()?);
+
+        let clients = Arc::new(Clients::default());
+
+        let accepted_clients = clients.clone();
+        spawn_for_car(move || accept_clients(listener, accepted_clients))?;
+
+        let (frames, received_frames) = sync_channel(1);
+        let streamed_clients = clients.clone();
+        spawn_for_car(move || stream_frames(received_frames, streamed_clients))?;
+
+        Ok(TelemetryServer { frames, clients })
+    }
+
+    pub fn has_clients(&self) -> bool {
+        self.clients.count.load(Ordering::Relaxed) > 0
+    }
+
+    /// Queues a frame for sending. Never blocks: the frame is dropped if the previous one hasn't
+    /// been sent yet.
+    pub fn send(&self, record: &TelemetryRecord, annotated_frame: &Mat) {
+        if !self.has_clients() {
+            return;
+        }
+
+        match self
+            .frames
+            .try_send((record.clone(), annotated_frame.clone()))
+        {
+            Ok(()) | Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Disconnected(_)) => log_error!("Telemetry server thread has stopped"),
+        }
+    }
+}
+
+/// Spawns a thread named like the current one, so its messages are labelled with the car that
+/// the server streams.
+fn spawn_for_car<F>(f: F) -> std::io::Result<thread::JoinHandle<()>>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut builder = thread::Builder::new();
+    if let Some(name) = thread::current().name() {
+        builder = builder.name(name.to_string());
+    }
+    builder.spawn(f)
+}
+
+fn accept_clients(listener: TcpListener, clients: Arc<Clients>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                log_error!("Failed to accept telemetry client: {}", error);
+                continue;
+            }
+        };
+
+        stream.set_nodelay(true).ok();
+        stream.set_write_timeout(Some(Duration::from_secs(1))).ok();
+
+        if let Ok(address) = stream.peer_addr() {
+            log!("Telemetry client connected from {}", address);
+        }
+
+        let mut streams = clients.streams.lock().unwrap();
+        streams.push(stream);
+        clients.count.store(streams.len(), Ordering::Relaxed);
+    }
+}
+
+fn stream_frames(frames: Receiver<(TelemetryRecord, Mat)>, clients: Arc<Clients>) {
+    for (record, frame) in frames {
+        let message = match encode_message(&record, &frame) {
+            Ok(message) => message,
+            Err(error) => {
+                log_error!("Failed to encode telemetry frame: {}", error);
+                continue;
+            }
+        };
+
+        // Writing can take up to the write timeout, so it's done without holding the lock and
+        // clients accepted meanwhile are added back to.
+        let mut sending = std::mem::take(&mut *clients.streams.lock().unwrap());
+        sending.retain(|mut client| client.write_all(&message).is_ok());
+
+        let mut streams = clients.streams.lock().unwrap();
+        streams.append(&mut sending);
+        clients.count.store(streams.len(), Ordering::Relaxed);
+    }
+}
+
+fn encode_message(record: &TelemetryRecord, frame: &Mat) -> anyhow::Result<Vec<u8>> {
+    let json = serde_json::to_vec(record)?;
+
+    let mut params = opencv::core::Vector::<i32>::new();
+    params.push(imgcodecs::IMWRITE_JPEG_QUALITY);
+    params.push(JPEG_QUALITY);
+
+    let mut jpeg = VectorOfu8::new();
+    imgcodecs::imencode(".jpg", frame, &mut jpeg, &params)?;
+    let jpeg = jpeg.to_vec();
+
+    let mut message = Vec::with_capacity(8 + json.len() + jpeg.len());
+    message.extend_from_slice(&(json.len() as u32).to_be_bytes());
+    message.extend_from_slice(&json);
+    message.extend_from_slice(&(jpeg.len() as u32).to_be_bytes());
+    message.extend_from_slice(&jpeg);
+
+    Ok(message)
+}
+
+/// Reads one message sent by `stream_frames`, None when the server has closed the connection.
+fn read_message(stream: &mut impl Read) -> anyhow::Result<Option<(TelemetryRecord, Mat)>> {
+    let json = match read_chunk(stream) {
+        Ok(json) => json,
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    let jpeg = read_chunk(stream)?;
+
+    let record = serde_json::from_slice(&json)?;
+    let frame = imgcodecs::imdecode(&VectorOfu8::from(jpeg), imgcodecs::IMREAD_COLOR)?;
+    if frame.empty()? {
+        bail!("Received a frame that is not a JPEG");
+    }
+
+    Ok(Some((record, frame)))
+}
+
+/// Reads a big-endian `u32` length and that many bytes.
+fn read_chunk(stream: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+
+    let mut chunk = vec![0; u32::from_be_bytes(length) as usize];
+    stream.read_exact(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Shows the stream of a telemetry server until it closes or `q` or Esc is pressed.
+pub fn watch(args: &[String]) -> anyhow::Result<()> {
+    let address = match args.first() {
+        Some(address) => address,
+        None => bail!("Usage: watch <address>"),
+    };
+
+    let mut stream = TcpStream::connect(address.as_str())?;
+    println!("Watching {}", address);
+    highgui::named_window(WATCH_WINDOW, highgui::WINDOW_AUTOSIZE)?;
+
+    while let Some((record, frame)) = read_message(&mut stream)? {
+        println!("{}", serde_json::to_string(&record)?);
+        highgui::imshow(WATCH_WINDOW, &frame)?;
+
+        let key = highgui::wait_key(1)?;
+        if key == 27 || key == 'q' as i32 {
+            break;
+        }
+    }
+
+    highgui::destroy_all_windows()?;
+    Ok(())
+}
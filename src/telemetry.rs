@@ -0,0 +1,278 @@
+//! Per-frame telemetry log.
+//!
+//! The output is chosen with the `TELEMETRY` environment variable: a path ending in `.jsonl`
+//! writes JSON Lines, any other path writes CSV and `off` disables logging. Defaults to
+//! `telemetry.csv` in the session directory.
+//!
+//! Every format is written from `TelemetryRecord`, so new columns must be appended to the end
+//! of the struct to keep old plotting scripts working.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{recovery::RecoveryPhase, turnaround::TurnaroundLeg, watchdog::Fault};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelemetryRecord {
+    pub frame: usize,
+    pub time_ms: u64,
+    pub horizon: i32,
+    pub horizon_interpolated: i32,
+    pub blue_ratio: f32,
+    pub green_ratio: f32,
+    pub red_ratio: f32,
+    pub speed: f32,
+    pub wheels_turn: f32,
+    pub forward: f32,
+    pub turn: f32,
+    pub read_ms: f32,
+    pub decode_ms: f32,
+    pub update_ms: f32,
+    pub fault: Option<Fault>,
+    /// Frames so far that couldn't be decoded, were invalid or failed to process.
+    #[serde(default)]
+    pub frame_errors: usize,
+    /// Bad frames so far that were replaced by the last good one.
+    #[serde(default)]
+    pub frames_reused: usize,
+    /// Whether the lane could be fitted to this frame, the estimate below is a prediction if not.
+    #[serde(default)]
+    pub lane_detected: bool,
+    #[serde(default)]
+    pub lane_offset: f32,
+    #[serde(default)]
+    pub lane_heading: f32,
+    #[serde(default)]
+    pub lane_curvature: f32,
+    /// Odometry: meters driven, position and heading relative to the start.
+    #[serde(default)]
+    pub distance: f32,
+    #[serde(default)]
+    pub position_x: f32,
+    #[serde(default)]
+    pub position_y: f32,
+    #[serde(default)]
+    pub heading: f32,
+    #[serde(default)]
+    pub lap_progress: Option<f32>,
+    /// Meters into the lap of the learned track map.
+    #[serde(default)]
+    pub track_position: Option<f32>,
+    /// Obstacles detected, with the position, closeness and distance of the nearest one.
+    #[serde(default)]
+    pub obstacles: usize,
+    #[serde(default)]
+    pub obstacle_position: Option<f32>,
+    #[serde(default)]
+    pub obstacle_closeness: Option<f32>,
+    #[serde(default)]
+    pub obstacle_distance: Option<f32>,
+    /// Laps completed, and the time of the lap completed on this frame in seconds.
+    #[serde(default)]
+    pub laps: usize,
+    #[serde(default)]
+    pub lap_time: Option<f32>,
+    /// Phase of the off-track recovery in control of the car.
+    #[serde(default)]
+    pub recovery: Option<RecoveryPhase>,
+    /// Whether the car is driving the wrong way, and the leg of the turnaround if turning.
+    #[serde(default)]
+    pub wrong_way: bool,
+    #[serde(default)]
+    pub turnaround: Option<TurnaroundLeg>,
+}
+
+pub enum Telemetry {
+    Off,
+    Csv(Box<csv::Writer<File>>),
+    JsonLines(BufWriter<File>),
+}
+
+impl Telemetry {
+    pub fn from_env(session_dir: &Path) -> anyhow::Result<Telemetry> {
+        match std::env::var("TELEMETRY") {
+            Ok(path) if path == "off" => Ok(Telemetry::Off),
+            Ok(path) => Telemetry::create(Path::new(&path)),
+            Err(_) => Telemetry::create(&session_dir.join("telemetry.csv")),
+        }
+    }
+
+    pub fn create(path: &Path) -> anyhow::Result<Telemetry> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = File::create(path)?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") => Ok(Telemetry::JsonLines(BufWriter::new(file))),
+            _ => Ok(Telemetry::Csv(Box::new(csv::Writer::from_writer(file)))),
+        }
+    }
+
+    pub fn log(&mut self, record: &TelemetryRecord) -> anyhow::Result<()> {
+        match self {
+            Telemetry::Off => {}
+            Telemetry::Csv(writer) => writer.serialize(record)?,
+            Telemetry::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        match self {
+            Telemetry::Off => {}
+            Telemetry::Csv(writer) => writer.flush()?,
+            Telemetry::JsonLines(writer) => writer.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads back a telemetry log written in either format.
+pub fn read(path: &Path) -> anyhow::Result<Vec<TelemetryRecord>> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("jsonl") => BufReader::new(File::open(path)?)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect(),
+        _ => csv::Reader::from_path(path)?
+            .deserialize()
+            .map(|record| Ok(record?))
+            .collect(),
+    }
+}
+
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn record() -> TelemetryRecord {
+        TelemetryRecord {
+            frame: 12,
+            time_ms: 1_600_000_000_123,
+            horizon: 18,
+            horizon_interpolated: 19,
+            blue_ratio: 0.25,
+            green_ratio: 0.03,
+            red_ratio: 0.02,
+            speed: 0.5,
+            wheels_turn: -0.1,
+            forward: -0.06,
+            turn: 0.9,
+            read_ms: 1.5,
+            decode_ms: 0.5,
+            update_ms: 2.0,
+            fault: Some(Fault::VisionLost),
+            frame_errors: 2,
+            frames_reused: 1,
+            lane_detected: true,
+            lane_offset: 0.1,
+            lane_heading: -0.2,
+            lane_curvature: 0.05,
+            distance: 12.5,
+            position_x: 3.0,
+            position_y: -4.0,
+            heading: 1.25,
+            lap_progress: Some(0.5),
+            track_position: None,
+            obstacles: 1,
+            obstacle_position: Some(-0.3),
+            obstacle_closeness: Some(0.7),
+            obstacle_distance: None,
+            laps: 3,
+            lap_time: Some(31.5),
+            recovery: Some(RecoveryPhase::Reverse),
+            wrong_way: true,
+            turnaround: None,
+        }
+    }
+
+    /// Path in the temp directory that is unique to this process and `name`.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("telemetry-test-{}-{}", std::process::id(), name))
+    }
+
+    /// Logs `record` to `path` and reads the log back.
+    fn round_trip(path: &Path, record: &TelemetryRecord) -> Vec<TelemetryRecord> {
+        let mut telemetry = Telemetry::create(path).unwrap();
+        telemetry.log(record).unwrap();
+        telemetry.log(record).unwrap();
+        telemetry.flush().unwrap();
+        drop(telemetry);
+
+        let records = read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        records
+    }
+
+    fn assert_same(actual: &TelemetryRecord, expected: &TelemetryRecord) {
+        assert_eq!(
+            serde_json::to_value(actual).unwrap(),
+            serde_json::to_value(expected).unwrap()
+        );
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let records = round_trip(&temp_path("round-trip.csv"), &record());
+        assert_eq!(records.len(), 2);
+        assert_same(&records[1], &record());
+    }
+
+    #[test]
+    fn json_lines_round_trip() {
+        let records = round_trip(&temp_path("round-trip.jsonl"), &record());
+        assert_eq!(records.len(), 2);
+        assert_same(&records[1], &record());
+    }
+
+    #[test]
+    fn reads_csv_without_later_columns() {
+        let path = temp_path("old.csv");
+        std::fs::write(
+            &path,
+            "frame,time_ms,horizon,horizon_interpolated,blue_ratio,green_ratio,red_ratio,speed,\
+             wheels_turn,forward,turn,read_ms,decode_ms,update_ms\n\
+             7,1600000000000,20,21,0.5,0.03,0.02,0.4,0.1,0.3,0.2,1.0,0.5,2.0\n",
+        )
+        .unwrap();
+
+        let records = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.frame, 7);
+        assert_eq!(record.horizon_interpolated, 21);
+        assert_eq!(record.update_ms, 2.0);
+        assert_eq!(record.fault, None);
+        assert_eq!(record.frame_errors, 0);
+        assert!(!record.lane_detected);
+        assert_eq!(record.lap_progress, None);
+        assert_eq!(record.recovery, None);
+        assert!(!record.wrong_way);
+        assert_eq!(record.turnaround, None);
+    }
+}
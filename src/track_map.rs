@@ -0,0 +1,302 @@
+//! Learns the track during the first lap and plans the speed of the following ones.
+//!
+//! With `learn` set and no stored map, the car drives a cautious first lap at `learning_forward`
+//! while the odometry poses are recorded. The lap is closed once the car has turned a full
+//! circle and is back within `closure_radius` of where it started. The recorded path is then cut
+//! into segments of `segment_length` meters, each getting its curvature and the highest forward
+//! value that is safe for it and the segments within `lookahead`, and saved to `path`.
+//!
+//! A map found at `path` on start is reused, assuming the car starts from the same place as
+//! when it was learned.
+
+use std::{
+    f32::consts::PI,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::odometry::Pose;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrackMapConfig {
+    pub path: PathBuf,
+    /// Learn the track if there's no map at `path`.
+    pub learn: bool,
+    pub learning_forward: f32,
+    pub segment_length: f32,
+    /// Shortest distance that can be a lap, in meters.
+    pub min_lap_length: f32,
+    /// Largest distance from the start at which the lap is closed, in meters.
+    pub closure_radius: f32,
+    /// Largest difference from a full circle at which the lap is closed, in radians.
+    pub closure_heading: f32,
+    /// Width of the track between the lines, in meters.
+    pub track_width: f32,
+    /// Forward value on a straight.
+    pub max_forward: f32,
+    pub min_forward: f32,
+    /// How strongly curvature lowers the forward value.
+    pub curvature_slowdown: f32,
+    /// Distance ahead that is already driven at its planned speed, in meters.
+    pub lookahead: f32,
+}
+
+impl Default for TrackMapConfig {
+    fn default() -> Self {
+        TrackMapConfig {
+            path: PathBuf::from("tracks/default.json"),
+            learn: false,
+            learning_forward: 0.1,
+            segment_length: 0.25,
+            min_lap_length: 5.0,
+            closure_radius: 1.0,
+            closure_heading: 0.5,
+            track_width: 0.6,
+            max_forward: 0.2,
+            min_forward: 0.08,
+            curvature_slowdown: 1.0,
+            lookahead: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Segment {
+    /// Distance from the start of the lap to the start of the segment, in meters.
+    pub start: f32,
+    /// Centerline position and heading at the start of the segment.
+    pub x: f32,
+    pub y: f32,
+    pub heading: f32,
+    /// Heading change per meter, in radians.
+    pub curvature: f32,
+    pub max_forward: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackProfile {
+    pub lap_length: f32,
+    pub width: f32,
+    pub segments: Vec<Segment>,
+}
+
+impl TrackProfile {
+    pub fn load(path: &Path) -> anyhow::Result<TrackProfile> {
+        let profile: TrackProfile = load_json(path, "track map")?;
+        profile
+            .validate()
+            .with_context(|| format!("Invalid track map {}", path.display()))?;
+        Ok(profile)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !(self.lap_length.is_finite() && self.lap_length > 0.0) {
+            bail!("lap_length has to be positive, got {}", self.lap_length);
+        }
+        if self.segments.is_empty() {
+            bail!("no segments");
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        save_json(self, path)
+    }
+
+    /// The segment `lap_distance` meters into the lap.
+    pub fn segment_at(&self, lap_distance: f32) -> &Segment {
+        let segment_length = self.lap_length / self.segments.len() as f32;
+        let i = (lap_distance.rem_euclid(self.lap_length) / segment_length) as usize;
+        &self.segments[i.min(self.segments.len() - 1)]
+    }
+
+    /// Builds the profile from the poses of one lap.
+    fn from_lap(poses: &[Pose], config: &TrackMapConfig) -> TrackProfile {
+        let start = poses[0];
+        let lap_length = poses[poses.len() - 1].distance - start.distance;
+        let count = ((lap_length / config.segment_length).round() as usize).max(1);
+        let segment_length = lap_length / count as f32;
+
+        let mut samples = Vec::with_capacity(count + 1);
+        let mut next = 0;
+        for i in 0..=count {
+            let distance = start.distance + i as f32 * segment_length;
+            while next + 1 < poses.len() - 1 && poses[next + 1].distance < distance {
+                next += 1;
+            }
+            samples.push(interpolate(&poses[next], &poses[next + 1], distance));
+        }
+
+        let base_forward: Vec<f32> = samples
+            .windows(2)
+            .map(|pair| {
+                let curvature = (pair[1].heading - pair[0].heading) / segment_length;
+                (config.max_forward / (1.0 + config.curvature_slowdown * curvature.abs()))
+                    .max(config.min_forward)
+            })
+            .collect();
+
+        // Brake early enough for what's within the lookahead, wrapping around the lap.
+        let ahead = (config.lookahead / segment_length).ceil() as usize;
+        let segments = samples
+            .windows(2)
+            .enumerate()
+            .map(|(i, pair)| Segment {
+                start: i as f32 * segment_length,
+                x: pair[0].x - start.x,
+                y: pair[0].y - start.y,
+                heading: pair[0].heading - start.heading,
+                curvature: (pair[1].heading - pair[0].heading) / segment_length,
+                max_forward: (i..=i + ahead)
+                    .map(|j| base_forward[j % count])
+                    .fold(f32::INFINITY, f32::min),
+            })
+            .collect();
+
+        TrackProfile {
+            lap_length,
+            width: config.track_width,
+            segments,
+        }
+    }
+}
+
+/// Reads a track file, `kind` names what it holds in errors.
+pub fn load_json<T: DeserializeOwned>(path: &Path, kind: &str) -> anyhow::Result<T> {
+    let file = File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    serde_json::from_reader(file).with_context(|| format!("Invalid {} {}", kind, path.display()))
+}
+
+/// Writes a track file, creating its directory if needed.
+pub fn save_json<T: Serialize>(value: &T, path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    serde_json::to_writer_pretty(File::create(path)?, value)?;
+    Ok(())
+}
+
+fn interpolate(a: &Pose, b: &Pose, distance: f32) -> Pose {
+    let span = b.distance - a.distance;
+    let t = if span > 0.0 {
+        ((distance - a.distance) / span).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    Pose {
+        x: a.x + (b.x - a.x) * t,
+        y: a.y + (b.y - a.y) * t,
+        heading: a.heading + (b.heading - a.heading) * t,
+        distance,
+    }
+}
+
+enum State {
+    /// Driving without a map.
+    Unmapped,
+    Learning(Vec<Pose>),
+    /// Following a map, with the odometry distance at which the current lap of it started.
+    Mapped(TrackProfile, f32),
+}
+
+pub struct TrackMap {
+    config: TrackMapConfig,
+    state: State,
+}
+
+impl TrackMap {
+    pub fn new(config: TrackMapConfig) -> anyhow::Result<TrackMap> {
+        let state = if config.path.exists() {
+            let profile = TrackProfile::load(&config.path)?;
+            log!(
+                "Loaded a {:.1} m track from {}",
+                profile.lap_length,
+                config.path.display()
+            );
+            State::Mapped(profile, 0.0)
+        } else if config.learn {
+            log!("Learning the track during the first lap");
+            State::Learning(Vec::new())
+        } else {
+            State::Unmapped
+        };
+
+        Ok(TrackMap { config, state })
+    }
+
+    /// Records `pose` while learning. Returns the forward value to drive with, None to leave it
+    /// to the controller.
+    pub fn update(&mut self, pose: Pose) -> Option<f32> {
+        if let State::Learning(poses) = &mut self.state {
+            poses.push(pose);
+            if self.lap_closed() {
+                self.finish_learning();
+            }
+            return Some(self.config.learning_forward);
+        }
+
+        let position = self.position(&pose)?;
+        Some(self.profile()?.segment_at(position).max_forward)
+    }
+
+    /// Meters into the mapped lap, None without a map.
+    pub fn position(&self, pose: &Pose) -> Option<f32> {
+        match &self.state {
+            State::Mapped(profile, lap_start) => {
+                Some((pose.distance - lap_start).rem_euclid(profile.lap_length))
+            }
+            State::Unmapped | State::Learning(_) => None,
+        }
+    }
+
+    pub fn profile(&self) -> Option<&TrackProfile> {
+        match &self.state {
+            State::Mapped(profile, _) => Some(profile),
+            State::Unmapped | State::Learning(_) => None,
+        }
+    }
+
+    fn lap_closed(&self) -> bool {
+        let poses = match &self.state {
+            State::Learning(poses) if poses.len() > 1 => poses,
+            _ => return false,
+        };
+
+        let start = poses[0];
+        let current = poses[poses.len() - 1];
+        let turned = (current.heading - start.heading).abs();
+        let distance_from_start = (current.x - start.x).hypot(current.y - start.y);
+
+        current.distance - start.distance >= self.config.min_lap_length
+            && (turned - 2.0 * PI).abs() <= self.config.closure_heading
+            && distance_from_start <= self.config.closure_radius
+    }
+
+    fn finish_learning(&mut self) {
+        let poses = match &self.state {
+            State::Learning(poses) => poses,
+            _ => return,
+        };
+
+        let profile = TrackProfile::from_lap(poses, &self.config);
+        let lap_start = poses[poses.len() - 1].distance;
+        log!(
+            "Learned a {:.1} m track in {} segments",
+            profile.lap_length,
+            profile.segments.len()
+        );
+
+        match profile.save(&self.config.path) {
+            Ok(()) => log!("Saved the track map to {}", self.config.path.display()),
+            Err(error) => log_error!("Could not save the track map: {}", error),
+        }
+
+        self.state = State::Mapped(profile, lap_start);
+    }
+}
@@ -0,0 +1,273 @@
+//! Detection of driving the wrong way around the track, and the turn that sets it right.
+//!
+//! The red line is expected on the right and the green one on the left, or the other way round
+//! if `red_on_right` is unset. A frame is driven the wrong way when most of the rows with both
+//! lines found, and at least `min_rows`, have them swapped. After `wrong_way_frames` such frames
+//! in a row the car turns around in legs of `leg_ms`, alternating forward with the wheels
+//! turned to one side and reverse with them turned to the other, until the lines have been the
+//! right way round for `right_way_frames` frames or `max_turn_ms` has passed.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{estimator::Lane, vision::TrackRow};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TurnaroundConfig {
+    pub enabled: bool,
+    pub red_on_right: bool,
+    /// Rows with both lines needed to tell the direction.
+    pub min_rows: usize,
+    pub wrong_way_frames: usize,
+    pub right_way_frames: usize,
+    pub leg_ms: u64,
+    pub forward: f32,
+    /// Forward value of the reversing legs, negative.
+    pub reverse_forward: f32,
+    pub turn: f32,
+    /// Longest turnaround before control is handed back to the controller.
+    pub max_turn_ms: u64,
+}
+
+impl Default for TurnaroundConfig {
+    fn default() -> Self {
+        TurnaroundConfig {
+            enabled: true,
+            red_on_right: true,
+            min_rows: 3,
+            wrong_way_frames: 10,
+            right_way_frames: 5,
+            leg_ms: 1200,
+            forward: 0.06,
+            reverse_forward: -0.06,
+            turn: 0.9,
+            max_turn_ms: 8000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TurnaroundLeg {
+    Forward,
+    Reverse,
+}
+
+/// Direction the car is facing on a frame, judged from which side each line is on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    RightWay,
+    WrongWay,
+    Unknown,
+}
+
+struct Turn {
+    started: Instant,
+    leg: TurnaroundLeg,
+    leg_started: Instant,
+    /// Side the wheels turn to on the forward legs, 1 for right and -1 for left.
+    side: f32,
+}
+
+pub struct Turnaround {
+    config: TurnaroundConfig,
+    turn: Option<Turn>,
+    wrong_way_frames: usize,
+    right_way_frames: usize,
+}
+
+impl Turnaround {
+    pub fn new(config: TurnaroundConfig) -> Turnaround {
+        Turnaround {
+            config,
+            turn: None,
+            wrong_way_frames: 0,
+            right_way_frames: 0,
+        }
+    }
+
+    pub fn leg(&self) -> Option<TurnaroundLeg> {
+        self.turn.as_ref().map(|turn| turn.leg)
+    }
+
+    /// Whether the car has been driving the wrong way long enough to turn around.
+    pub fn wrong_way(&self) -> bool {
+        self.wrong_way_frames >= self.config.wrong_way_frames
+    }
+
+    /// Checks the line positions of a frame seen at `now`. Returns the forward and turn values
+    /// of the turnaround, None to leave the car to the controller.
+    pub fn update(&mut self, rows: &[TrackRow], lane: Lane, now: Instant) -> Option<(f32, f32)> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        match self.direction(rows) {
+            Direction::WrongWay => {
+                self.wrong_way_frames += 1;
+                self.right_way_frames = 0;
+            }
+            Direction::RightWay => {
+                self.wrong_way_frames = 0;
+                self.right_way_frames += 1;
+            }
+            Direction::Unknown => {}
+        }
+
+        let config = &self.config;
+        match &mut self.turn {
+            None if self.wrong_way_frames >= config.wrong_way_frames => {
+                log_error!("Driving the wrong way, turning around");
+                self.right_way_frames = 0;
+                // Turn towards the side with more room, which is where the centerline is.
+                self.turn = Some(Turn {
+                    started: now,
+                    leg: TurnaroundLeg::Forward,
+                    leg_started: now,
+                    side: if lane.offset < 0.0 { -1.0 } else { 1.0 },
+                });
+            }
+            None => return None,
+            Some(_) if self.right_way_frames >= config.right_way_frames => {
+                log!("Turned around");
+                self.finish();
+                return None;
+            }
+            Some(turn) if now - turn.started >= Duration::from_millis(config.max_turn_ms) => {
+                log_error!("Could not turn around");
+                self.finish();
+                return None;
+            }
+            Some(turn) => {
+                if now - turn.leg_started >= Duration::from_millis(config.leg_ms) {
+                    turn.leg = match turn.leg {
+                        TurnaroundLeg::Forward => TurnaroundLeg::Reverse,
+                        TurnaroundLeg::Reverse => TurnaroundLeg::Forward,
+                    };
+                    turn.leg_started = now;
+                }
+            }
+        }
+
+        let turn = self.turn.as_ref()?;
+        let config = &self.config;
+        Some(match turn.leg {
+            TurnaroundLeg::Forward => (config.forward, turn.side * config.turn),
+            TurnaroundLeg::Reverse => (config.reverse_forward, -turn.side * config.turn),
+        })
+    }
+
+    fn finish(&mut self) {
+        self.turn = None;
+        self.wrong_way_frames = 0;
+    }
+
+    fn direction(&self, rows: &[TrackRow]) -> Direction {
+        let (swapped, total) = rows
+            .iter()
+            .filter_map(|row| Some((row.green?, row.red?)))
+            .fold((0, 0), |(swapped, total), (green, red)| {
+                let red_on_right = red > green;
+                let swapped_row = red_on_right != self.config.red_on_right;
+                (swapped + swapped_row as usize, total + 1)
+            });
+
+        if total < self.config.min_rows {
+            Direction::Unknown
+        } else if swapped * 2 > total {
+            Direction::WrongWay
+        } else {
+            Direction::RightWay
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(green: i32, red: i32, count: usize) -> Vec<TrackRow> {
+        (0..count)
+            .map(|i| TrackRow {
+                y: i as i32,
+                green: Some(green),
+                red: Some(red),
+            })
+            .collect()
+    }
+
+    fn lane(offset: f32) -> Lane {
+        Lane {
+            offset,
+            heading: 0.0,
+            curvature: 0.0,
+        }
+    }
+
+    #[test]
+    fn red_on_the_right_is_the_right_way() {
+        let turnaround = Turnaround::new(TurnaroundConfig::default());
+        assert_eq!(turnaround.direction(&rows(20, 100, 5)), Direction::RightWay);
+        assert_eq!(turnaround.direction(&rows(100, 20, 5)), Direction::WrongWay);
+    }
+
+    #[test]
+    fn red_on_the_left_when_configured() {
+        let turnaround = Turnaround::new(TurnaroundConfig {
+            red_on_right: false,
+            ..TurnaroundConfig::default()
+        });
+        assert_eq!(turnaround.direction(&rows(20, 100, 5)), Direction::WrongWay);
+        assert_eq!(turnaround.direction(&rows(100, 20, 5)), Direction::RightWay);
+    }
+
+    #[test]
+    fn direction_needs_rows_with_both_lines() {
+        let turnaround = Turnaround::new(TurnaroundConfig::default());
+        assert_eq!(turnaround.direction(&rows(100, 20, 2)), Direction::Unknown);
+
+        let mut one_line = rows(100, 20, 5);
+        for row in &mut one_line[1..] {
+            row.green = None;
+        }
+        assert_eq!(turnaround.direction(&one_line), Direction::Unknown);
+    }
+
+    #[test]
+    fn direction_follows_the_majority_of_rows() {
+        let turnaround = Turnaround::new(TurnaroundConfig::default());
+        let mut mixed = rows(100, 20, 3);
+        mixed.extend(rows(20, 100, 2));
+        assert_eq!(turnaround.direction(&mixed), Direction::WrongWay);
+
+        mixed.extend(rows(20, 100, 1));
+        assert_eq!(turnaround.direction(&mixed), Direction::RightWay);
+    }
+
+    #[test]
+    fn turns_towards_the_centerline() {
+        let config = TurnaroundConfig {
+            wrong_way_frames: 2,
+            ..TurnaroundConfig::default()
+        };
+        let leg = Duration::from_millis(config.leg_ms);
+        let mut turnaround = Turnaround::new(config.clone());
+        let wrong_way = rows(100, 20, 5);
+        let start = Instant::now();
+
+        assert_eq!(turnaround.update(&wrong_way, lane(-0.2), start), None);
+        assert_eq!(
+            turnaround.update(&wrong_way, lane(-0.2), start),
+            Some((config.forward, -config.turn))
+        );
+        assert_eq!(turnaround.leg(), Some(TurnaroundLeg::Forward));
+
+        // The reversing leg turns the wheels the other way.
+        assert_eq!(
+            turnaround.update(&wrong_way, lane(-0.2), start + leg),
+            Some((config.reverse_forward, config.turn))
+        );
+        assert_eq!(turnaround.leg(), Some(TurnaroundLeg::Reverse));
+    }
+}
@@ -0,0 +1,315 @@
+use anyhow::anyhow;
+use opencv::{
+    core::{
+        bitwise_and, count_non_zero, in_range, normalize, split, Point_, Rect_, Scalar_, Size,
+        BORDER_CONSTANT, NORM_MINMAX,
+    },
+    imgcodecs::{imdecode, IMREAD_COLOR},
+    imgproc::{
+        cvt_color, erode, get_structuring_element, morphology_default_border_value, threshold,
+        COLOR_BGR2GRAY, MORPH_RECT, THRESH_BINARY,
+    },
+    prelude::*,
+    types::{VectorOfMat, VectorOfu8},
+};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use crate::{
+    debug::DebugOutput,
+    error::{Error, Result},
+};
+
+/// Per-channel output of `process_frame`: the eroded channel followed by the blue, green and
+/// red threshold masks computed from it. Only the mask matching the channel is used.
+pub type ChannelFrames = (Mat, Mat, Mat, Mat);
+
+/// Tunable thresholds of `process_frame`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VisionParams {
+    /// Gray level under which a pixel counts as black.
+    pub black_threshold: f64,
+    pub blue_threshold: f64,
+    pub green_threshold: f64,
+    pub red_threshold: f64,
+    /// Side length of the erosion kernel, in pixels.
+    pub erode_size: i32,
+}
+
+impl Default for VisionParams {
+    fn default() -> Self {
+        VisionParams {
+            black_threshold: 30.0,
+            blue_threshold: 200.0,
+            green_threshold: 200.0,
+            red_threshold: 150.0,
+            erode_size: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Measurements {
+    pub horizon: i32,
+    pub blue_ratio: f32,
+    pub green_ratio: f32,
+    pub red_ratio: f32,
+}
+
+/// Share of black pixels in the red mask for every row in the upper half of the frame, bottom
+/// row first. These are the candidates `find_horizon` picks from.
+pub fn horizon_scores(frames: &[ChannelFrames]) -> Result<Vec<(i32, f32)>> {
+    let filtered = &frames[2].3;
+    let cols = filtered.cols();
+
+    (0..filtered.rows() / 2)
+        .rev()
+        .map(|y| {
+            let row = filtered.at_row::<u8>(y)?;
+            let black_pixel_count = row.iter().filter(|px| **px == 0).count();
+            let fullness = black_pixel_count as f32 / cols as f32;
+            Ok((y, fullness))
+        })
+        .collect()
+}
+
+/// Finds the row in the upper half of the frame with the most black pixels in the red mask.
+pub fn find_horizon(frames: &[ChannelFrames]) -> Result<i32> {
+    let (horizon_i, _horizon_blackness) = horizon_scores(frames)?
+        .into_iter()
+        .max_by(|(_, fullness_a), (_, fullness_b)| {
+            PartialOrd::partial_cmp(fullness_a, fullness_b).unwrap_or(Ordering::Equal)
+        })
+        .ok_or_else(|| Error::Vision(String::from("Frame is too small to find the horizon")))?;
+
+    Ok(horizon_i)
+}
+
+/// Returns the region below `horizon`, which is what the controller looks at.
+pub fn roi_below(frames: &[ChannelFrames], horizon: i32) -> Rect_<i32> {
+    let width = frames[0].1.cols();
+    let height = frames[0].1.rows();
+
+    Rect_ {
+        x: 0,
+        y: horizon,
+        width,
+        height: height - horizon,
+    }
+}
+
+/// Mean columns of the green and red line pixels on one row of the frame.
+#[derive(Debug, Clone, Copy)]
+pub struct TrackRow {
+    pub y: i32,
+    pub green: Option<i32>,
+    pub red: Option<i32>,
+}
+
+impl TrackRow {
+    pub fn center(&self) -> Option<i32> {
+        Some((self.green? + self.red?) / 2)
+    }
+}
+
+fn mean_column(mask: &Mat, y: i32) -> Option<i32> {
+    let row = mask.at_row::<u8>(y).ok()?;
+    let (sum, count) = row
+        .iter()
+        .enumerate()
+        .filter(|(_, px)| **px > 0)
+        .fold((0, 0), |(sum, count), (x, _)| (sum + x, count + 1));
+
+    if count == 0 {
+        None
+    } else {
+        Some((sum / count) as i32)
+    }
+}
+
+/// Locates the green and red lines on every `step`th row of `roi`.
+pub fn find_track_edges(frames: &[ChannelFrames], roi: Rect_<i32>, step: usize) -> Vec<TrackRow> {
+    (roi.y..roi.y + roi.height)
+        .step_by(step)
+        .map(|y| TrackRow {
+            y,
+            green: mean_column(&frames[1].2, y),
+            red: mean_column(&frames[2].3, y),
+        })
+        .collect()
+}
+
+/// Computes the share of blue, green and red mask pixels below `horizon`.
+pub fn measure(
+    frames: &[ChannelFrames],
+    horizon: i32,
+    debug: &mut DebugOutput,
+) -> Result<Measurements> {
+    let roi_rect = roi_below(frames, horizon);
+    if roi_rect.height <= 0 || roi_rect.width <= 0 {
+        return Err(Error::Vision(String::from("Horizon is outside the frame")));
+    }
+    let total_pixels = roi_rect.width * roi_rect.height;
+
+    let blue_roi = Mat::roi(&frames[0].1, roi_rect)?;
+    debug.save("blue-roi", &blue_roi);
+    let blue_count = count_non_zero(&blue_roi)? as f32;
+
+    // calc the green frame and if we should move left
+    let green_roi = Mat::roi(&frames[1].2, roi_rect)?;
+    debug.save("green-roi", &green_roi);
+    let green_count = count_non_zero(&green_roi)? as f32;
+
+    // calc the red frame and if we should move right
+    let red_roi = Mat::roi(&frames[2].3, roi_rect)?;
+    debug.save("red-roi", &red_roi);
+    let red_count = count_non_zero(&red_roi)? as f32;
+
+    Ok(Measurements {
+        horizon,
+        blue_ratio: blue_count / total_pixels as f32,
+        green_ratio: green_count / total_pixels as f32,
+        red_ratio: red_count / total_pixels as f32,
+    })
+}
+
+/// Decodes an encoded image received from the simulator.
+pub fn decode_frame(image: Vec<u8>) -> Result<Mat> {
+    let frame = imdecode(&VectorOfu8::from(image), IMREAD_COLOR)
+        .map_err(|error| Error::Decode(error.message))?;
+
+    if frame.rows() == 0 || frame.cols() == 0 {
+        return Err(Error::Decode(String::from("Not an image")));
+    }
+
+    Ok(frame)
+}
+
+pub fn process_frame(
+    frame: &Mat,
+    params: &VisionParams,
+    debug: &mut DebugOutput,
+) -> Result<Vec<ChannelFrames>> {
+    debug.save("original", frame);
+
+    // calculate the blacks
+    let mut gray_scaled = frame.clone();
+    cvt_color(&frame, &mut gray_scaled, COLOR_BGR2GRAY, 0)?;
+    debug.save("graycolor", &gray_scaled);
+
+    let mut th = frame.clone();
+    threshold(
+        &mut gray_scaled,
+        &mut th,
+        params.black_threshold,
+        255.0,
+        THRESH_BINARY,
+    )?;
+
+    let kernel = get_structuring_element(
+        MORPH_RECT,
+        Size {
+            width: params.erode_size,
+            height: params.erode_size,
+        },
+        Point_ { x: -1, y: -1 },
+    )?;
+
+    let mut blacks = frame.clone();
+    erode(
+        &th,
+        &mut blacks,
+        &kernel,
+        Point_ { x: -1, y: -1 },
+        1,
+        BORDER_CONSTANT,
+        morphology_default_border_value()?,
+    )?;
+
+    let mask = Mat::default()?;
+    let mut normalized = frame.clone();
+    normalize(&frame, &mut normalized, 0.0, 255.0, NORM_MINMAX, -1, &mask)?;
+
+    let mut preprosessed_image = frame.clone();
+    bitwise_and(&normalized, &normalized, &mut preprosessed_image, &blacks)?;
+
+    let split_frame_red = frame.clone();
+    let split_frame_green = frame.clone();
+    let split_frame_blue = frame.clone();
+    let mut split_frame = VectorOfMat::new();
+    split_frame.push(split_frame_blue);
+    split_frame.push(split_frame_green);
+    split_frame.push(split_frame_red);
+    split(&preprosessed_image, &mut split_frame)?;
+
+    // erode the blue green and red
+    let split_frame_processed = split_frame
+        .iter()
+        .map(|c| -> Result<ChannelFrames> {
+            let mut r = c.clone();
+            erode(
+                &c,
+                &mut r,
+                &kernel,
+                Point_ { x: -1, y: -1 },
+                1,
+                BORDER_CONSTANT,
+                morphology_default_border_value()?,
+            )?;
+
+            // 100 is good for red, blue and green is good with 120
+            let mut rred = c.clone();
+            threshold(&r, &mut rred, params.red_threshold, 255.0, THRESH_BINARY)?;
+
+            let mut rgreen = c.clone();
+            threshold(
+                &r,
+                &mut rgreen,
+                params.green_threshold,
+                255.0,
+                THRESH_BINARY,
+            )?;
+
+            let mut rblue = c.clone();
+            threshold(&r, &mut rblue, params.blue_threshold, 255.0, THRESH_BINARY)?;
+
+            Ok((r, rblue, rgreen, rred))
+        })
+        .collect::<Result<Vec<ChannelFrames>>>()?;
+
+    debug.save("blue-0", &split_frame_processed[0].0);
+    debug.save("blue-1", &split_frame_processed[0].1);
+    debug.save("green-0", &split_frame_processed[1].0);
+    debug.save("green-1", &split_frame_processed[1].2);
+    debug.save("red-0", &split_frame_processed[2].0);
+    debug.save("red-1", &split_frame_processed[2].3);
+
+    Ok(split_frame_processed)
+}
+
+/// Mask of the pixels of `frame` within `tolerance` of the BGR `color` in every channel.
+pub fn color_mask(frame: &Mat, [blue, green, red]: [f64; 3], tolerance: f64) -> Result<Mat> {
+    let lower = Scalar_::new(blue - tolerance, green - tolerance, red - tolerance, 0.0);
+    let upper = Scalar_::new(blue + tolerance, green + tolerance, red + tolerance, 0.0);
+
+    let mut mask = Mat::default()?;
+    in_range(frame, &lower, &upper, &mut mask)?;
+    Ok(mask)
+}
+
+/// Parses `#rrggbb` into its blue, green and red components.
+pub fn parse_color(color: &str) -> anyhow::Result<[f64; 3]> {
+    let invalid = || anyhow!("Invalid color {:?}, expected #rrggbb", color);
+    let hex = color.strip_prefix('#').ok_or_else(invalid)?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let component = |i: usize| {
+        u8::from_str_radix(&hex[i..i + 2], 16)
+            .map(f64::from)
+            .map_err(|_| invalid())
+    };
+    Ok([component(4)?, component(2)?, component(0)?])
+}
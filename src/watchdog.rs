@@ -0,0 +1,272 @@
+//! Safety watchdog that overrides the controller when its inputs or outputs can't be trusted.
+//!
+//! Frames arriving too slowly, losing sight of the track lines for too many frames in a row and
+//! non-finite or out-of-range commands all raise a `Fault`, during which the car is stopped or
+//! crawls forward with straight wheels depending on `WatchdogConfig::action`.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::vision::Measurements;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SafeAction {
+    Stop,
+    Crawl,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// Longest wait for the next frame before the car is stopped.
+    pub max_frame_interval_ms: u64,
+    /// Share of red and green pixels under which the frame counts as a vision failure.
+    pub min_line_ratio: f32,
+    /// Consecutive vision failures tolerated before the car is stopped.
+    pub max_vision_failures: usize,
+    pub max_forward: f32,
+    pub max_turn: f32,
+    pub action: SafeAction,
+    /// Forward value used by `SafeAction::Crawl`.
+    pub crawl_forward: f32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            max_frame_interval_ms: 500,
+            min_line_ratio: 0.002,
+            max_vision_failures: 10,
+            max_forward: 1.0,
+            max_turn: 1.0,
+            action: SafeAction::Stop,
+            crawl_forward: 0.02,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Fault {
+    StaleFrames,
+    VisionLost,
+    InvalidCommand,
+}
+
+pub struct Watchdog {
+    config: WatchdogConfig,
+    vision_failures: usize,
+    fault: Option<Fault>,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig) -> Watchdog {
+        Watchdog {
+            config,
+            vision_failures: 0,
+            fault: None,
+        }
+    }
+
+    pub fn frame_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.max_frame_interval_ms)
+    }
+
+    /// Called when no frame arrived within `frame_timeout`.
+    pub fn frame_missing(&mut self) {
+        self.set_fault(Some(Fault::StaleFrames));
+    }
+
+    /// Called when a frame was skipped because it couldn't be decoded or processed. Counts as a
+    /// vision failure.
+    pub fn frame_skipped(&mut self) -> Option<Fault> {
+        self.vision_failures += 1;
+        if self.vision_failures > self.config.max_vision_failures {
+            self.set_fault(Some(Fault::VisionLost));
+        }
+
+        self.fault
+    }
+
+    /// Checks a processed frame and the commands the controller wants to send for it.
+    pub fn check(&mut self, measurements: &Measurements, forward: f32, turn: f32) -> Option<Fault> {
+        let line_ratio = measurements.red_ratio + measurements.green_ratio;
+        if line_ratio.is_finite() && line_ratio >= self.config.min_line_ratio {
+            self.vision_failures = 0;
+        } else {
+            self.vision_failures += 1;
+        }
+
+        let fault = if self.invalid_command(forward, turn) {
+            Some(Fault::InvalidCommand)
+        } else if self.vision_failures > self.config.max_vision_failures {
+            Some(Fault::VisionLost)
+        } else {
+            None
+        };
+
+        self.set_fault(fault);
+        fault
+    }
+
+    /// Checks the commands of a maneuver that doesn't rely on seeing the track, such as an
+    /// off-track recovery. Vision failures are neither counted nor cleared.
+    pub fn check_maneuver(&mut self, forward: f32, turn: f32) -> Option<Fault> {
+        let fault = if self.invalid_command(forward, turn) {
+            Some(Fault::InvalidCommand)
+        } else {
+            None
+        };
+
+        self.set_fault(fault);
+        fault
+    }
+
+    fn invalid_command(&self, forward: f32, turn: f32) -> bool {
+        !forward.is_finite()
+            || !turn.is_finite()
+            || forward.abs() > self.config.max_forward
+            || turn.abs() > self.config.max_turn
+    }
+
+    /// Forward and turn values that replace the controller's while a fault is active.
+    pub fn safe_output(&self) -> (f32, f32) {
+        match self.config.action {
+            SafeAction::Stop => (0.0, 0.0),
+            SafeAction::Crawl => (self.config.crawl_forward, 0.0),
+        }
+    }
+
+    fn set_fault(&mut self, fault: Option<Fault>) {
+        if fault != self.fault {
+            match fault {
+                Some(fault) => log_error!("Watchdog: {:?}, {:?}", fault, self.config.action),
+                None => log!("Watchdog: recovered"),
+            }
+        }
+
+        self.fault = fault;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> WatchdogConfig {
+        WatchdogConfig {
+            max_vision_failures: 3,
+            ..WatchdogConfig::default()
+        }
+    }
+
+    fn measurements(line_ratio: f32) -> Measurements {
+        Measurements {
+            horizon: 0,
+            blue_ratio: 0.0,
+            green_ratio: line_ratio / 2.0,
+            red_ratio: line_ratio / 2.0,
+        }
+    }
+
+    #[test]
+    fn vision_lost_after_max_vision_failures() {
+        let mut watchdog = Watchdog::new(config());
+        for _ in 0..3 {
+            assert_eq!(watchdog.check(&measurements(0.0), 0.1, 0.0), None);
+        }
+        assert_eq!(
+            watchdog.check(&measurements(0.0), 0.1, 0.0),
+            Some(Fault::VisionLost)
+        );
+        assert_eq!(
+            watchdog.check(&measurements(f32::NAN), 0.1, 0.0),
+            Some(Fault::VisionLost)
+        );
+
+        assert_eq!(watchdog.check(&measurements(0.1), 0.1, 0.0), None);
+        assert_eq!(watchdog.check(&measurements(0.0), 0.1, 0.0), None);
+    }
+
+    #[test]
+    fn invalid_commands() {
+        let mut watchdog = Watchdog::new(config());
+        let seen = measurements(0.1);
+        assert_eq!(watchdog.check(&seen, 1.0, -1.0), None);
+
+        for &(forward, turn) in &[
+            (f32::NAN, 0.0),
+            (0.1, f32::NAN),
+            (f32::INFINITY, 0.0),
+            (1.5, 0.0),
+            (-1.5, 0.0),
+            (0.1, 1.5),
+            (0.1, -1.5),
+        ] {
+            assert_eq!(
+                watchdog.check(&seen, forward, turn),
+                Some(Fault::InvalidCommand),
+                "forward {} turn {}",
+                forward,
+                turn
+            );
+            assert_eq!(
+                watchdog.check_maneuver(forward, turn),
+                Some(Fault::InvalidCommand),
+                "forward {} turn {}",
+                forward,
+                turn
+            );
+        }
+    }
+
+    #[test]
+    fn maneuvers_leave_vision_failures_alone() {
+        let mut watchdog = Watchdog::new(config());
+        for _ in 0..3 {
+            watchdog.check(&measurements(0.0), 0.1, 0.0);
+        }
+
+        // Neither counted, which would trip the fourth failure, nor cleared.
+        for _ in 0..5 {
+            assert_eq!(watchdog.check_maneuver(-0.06, 0.9), None);
+        }
+        assert_eq!(
+            watchdog.check(&measurements(0.0), 0.1, 0.0),
+            Some(Fault::VisionLost)
+        );
+    }
+
+    #[test]
+    fn skipped_frames_escalate_to_vision_lost() {
+        let mut watchdog = Watchdog::new(config());
+        for _ in 0..3 {
+            assert_eq!(watchdog.frame_skipped(), None);
+        }
+        assert_eq!(watchdog.frame_skipped(), Some(Fault::VisionLost));
+
+        // A skipped frame also counts towards the failures of processed frames.
+        let mut watchdog = Watchdog::new(config());
+        watchdog.check(&measurements(0.0), 0.1, 0.0);
+        watchdog.check(&measurements(0.0), 0.1, 0.0);
+        watchdog.frame_skipped();
+        assert_eq!(
+            watchdog.check(&measurements(0.0), 0.1, 0.0),
+            Some(Fault::VisionLost)
+        );
+    }
+
+    #[test]
+    fn safe_output_stops_or_crawls_straight() {
+        let stop = Watchdog::new(config());
+        assert_eq!(stop.safe_output(), (0.0, 0.0));
+
+        let crawl = Watchdog::new(WatchdogConfig {
+            action: SafeAction::Crawl,
+            crawl_forward: 0.03,
+            ..config()
+        });
+        assert_eq!(crawl.safe_output(), (0.03, 0.0));
+    }
+}